@@ -0,0 +1,942 @@
+use async_trait::async_trait;
+use aws_config::{BehaviorVersion, SdkConfig};
+use aws_sdk_acm::types::ValidationMethod;
+use aws_sdk_ec2::config::Credentials;
+use aws_sdk_ec2::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_ec2::operation::RequestId;
+use aws_sdk_ec2::types::{
+    Filter, IpPermission, IpRange, Ipv6Range, LaunchTemplateIamInstanceProfileSpecificationRequest,
+    LaunchTemplateInstanceMetadataTagsState,
+    LaunchTemplateInstanceNetworkInterfaceSpecificationRequest, RequestLaunchTemplateData,
+    UserIdGroupPair,
+};
+use aws_sdk_elasticloadbalancingv2::types::{
+    Action, ActionTypeEnum, Certificate as ListenerCertificate, FixedResponseActionConfig,
+    HostHeaderConditionConfig, LoadBalancerSchemeEnum, PathPatternConditionConfig, ProtocolEnum,
+    RedirectActionConfig, RedirectActionStatusCodeEnum, RuleCondition,
+};
+use aws_sdk_route53::types::{
+    AliasTarget, Change, ChangeAction, ChangeBatch, ResourceRecord, ResourceRecordSet, RrType,
+};
+
+use crate::cloud::{
+    Certificate, CloudProvider, Instance, LoadBalancer, LoadBalancerState, TargetHealth,
+    ValidationRecord,
+};
+use crate::config::Environment;
+use crate::deployment::RecordSet;
+use crate::error::{CloudError, CloudErrorKind};
+use crate::plan::{
+    AutoScalingGroupPlan, CertificatePlan, IngressRulePlan, IngressSource, LaunchTemplatePlan,
+    ListenerAction, ListenerPlan, ListenerRulePlan, LoadBalancerPlan, SecurityGroupPlan,
+    TargetGroupPlan,
+};
+use crate::secrets::{self, SecretStore};
+
+/// Load the SDK configuration every client of `environment` shares: its
+/// region, credentials and endpoint override
+///
+/// Every AWS client must be built from this so that pointing an environment
+/// at an emulator redirects all of its requests.
+pub async fn sdk_config(environment: &Environment) -> SdkConfig {
+    let settings = &environment.aws;
+    let mut loader = aws_config::defaults(BehaviorVersion::latest())
+        .region(aws_config::Region::new(environment.region.clone()));
+    if let (Some(access_key_id), Some(secret_access_key)) =
+        (&settings.access_key_id, &settings.secret_access_key)
+    {
+        loader = loader.credentials_provider(Credentials::new(
+            access_key_id,
+            secret_access_key,
+            None,
+            None,
+            "configuration",
+        ));
+    } else if let Some(profile) = &settings.profile {
+        loader = loader.profile_name(profile);
+    }
+    if let Some(endpoint_url) = &settings.endpoint_url {
+        loader = loader.endpoint_url(endpoint_url);
+    }
+    loader.load().await
+}
+
+/// Provisions deployments with the AWS clients for one environment
+pub struct AwsProvider {
+    ec2_client: aws_sdk_ec2::Client,
+    as_client: aws_sdk_autoscaling::Client,
+    elb_client: aws_sdk_elasticloadbalancingv2::Client,
+    route53_client: aws_sdk_route53::Client,
+    acm_client: aws_sdk_acm::Client,
+    secret_store: Box<dyn SecretStore>,
+}
+
+impl AwsProvider {
+    pub async fn new(environment: &Environment) -> Self {
+        let config = sdk_config(environment).await;
+        Self {
+            ec2_client: aws_sdk_ec2::Client::new(&config),
+            as_client: aws_sdk_autoscaling::Client::new(&config),
+            elb_client: aws_sdk_elasticloadbalancingv2::Client::new(&config),
+            route53_client: aws_sdk_route53::Client::new(&config),
+            acm_client: aws_sdk_acm::Client::new(&config),
+            secret_store: secrets::from_config(&environment.secrets, &config),
+        }
+    }
+}
+
+/// Classify a failed AWS call by its error code, keeping the request id
+pub fn classify<E>(e: SdkError<E>) -> CloudError
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+{
+    // EC2 puts the request id in the error body rather than a header
+    let request_id = e
+        .request_id()
+        .or_else(|| e.meta().request_id())
+        .map(|id| id.to_string());
+    let error = match (&e, e.code()) {
+        (SdkError::ServiceError(_), Some(code)) => CloudError::from_code(
+            code,
+            e.message()
+                .map(|m| m.to_string())
+                .unwrap_or_else(|| DisplayErrorContext(&e).to_string()),
+            request_id.as_deref(),
+        ),
+        (SdkError::ServiceError(service), None) if service.raw().status().is_server_error() => {
+            CloudError::new(
+                CloudErrorKind::Unavailable,
+                DisplayErrorContext(&e).to_string(),
+            )
+        }
+        (
+            SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_),
+            _,
+        ) => CloudError::new(
+            CloudErrorKind::Unavailable,
+            DisplayErrorContext(&e).to_string(),
+        ),
+        _ => CloudError::new(CloudErrorKind::Other, DisplayErrorContext(&e).to_string()),
+    };
+    CloudError {
+        request_id,
+        ..error
+    }
+}
+
+/// A request that could not be built, e.g. because a required field is missing
+fn invalid<E: std::error::Error>(e: E) -> CloudError {
+    CloudError::new(
+        CloudErrorKind::InvalidRequest,
+        DisplayErrorContext(e).to_string(),
+    )
+}
+
+/// A successful response that is missing something we asked for
+fn incomplete(message: &str) -> CloudError {
+    CloudError::new(CloudErrorKind::Other, message)
+}
+
+/// The AWS APIs take 32 bit ports and sizes
+fn to_i32(field: &str, value: i64) -> Result<i32, CloudError> {
+    i32::try_from(value).map_err(|_| {
+        CloudError::new(
+            CloudErrorKind::InvalidRequest,
+            format!("{} {} is out of range", field, value),
+        )
+    })
+}
+
+/// The instance profile spec for `plan`, which may name the profile by name
+/// or by ARN
+fn instance_profile(
+    plan: &LaunchTemplatePlan,
+) -> Option<LaunchTemplateIamInstanceProfileSpecificationRequest> {
+    plan.instance_profile.as_ref().map(|profile| {
+        let builder = LaunchTemplateIamInstanceProfileSpecificationRequest::builder();
+        if profile.starts_with("arn:") {
+            builder.arn(profile).build()
+        } else {
+            builder.name(profile).build()
+        }
+    })
+}
+
+#[async_trait]
+impl CloudProvider for AwsProvider {
+    fn secrets(&self) -> &dyn SecretStore {
+        self.secret_store.as_ref()
+    }
+
+    async fn create_launch_template(
+        &self,
+        plan: &LaunchTemplatePlan,
+        security_group_id: &str,
+        user_data: Option<&str>,
+    ) -> Result<String, CloudError> {
+        let output = self
+            .ec2_client
+            .create_launch_template()
+            .set_launch_template_name(Some(plan.name.clone()))
+            .set_launch_template_data(Some(
+                RequestLaunchTemplateData::builder()
+                    .instance_type(aws_sdk_ec2::types::InstanceType::from(
+                        plan.instance_type.as_str(),
+                    ))
+                    .image_id(plan.image_id.clone())
+                    .set_user_data(user_data.map(str::to_string))
+                    .set_iam_instance_profile(instance_profile(plan))
+                    .network_interfaces(
+                        LaunchTemplateInstanceNetworkInterfaceSpecificationRequest::builder()
+                            .device_index(0)
+                            .groups(security_group_id)
+                            .build(),
+                    )
+                    .set_metadata_options(Some(
+                        aws_sdk_ec2::types::LaunchTemplateInstanceMetadataOptionsRequest::builder()
+                            .set_instance_metadata_tags(Some(
+                                LaunchTemplateInstanceMetadataTagsState::Enabled,
+                            ))
+                            .build(),
+                    ))
+                    .set_tag_specifications(Some(vec![
+                        aws_sdk_ec2::types::LaunchTemplateTagSpecificationRequest::builder()
+                            .set_resource_type(Some(aws_sdk_ec2::types::ResourceType::Instance))
+                            .set_tags(Some(
+                                plan.tags
+                                    .iter()
+                                    .map(|(k, v)| {
+                                        aws_sdk_ec2::types::Tag::builder()
+                                            .set_key(Some(k.clone()))
+                                            .set_value(Some(v.clone()))
+                                            .build()
+                                    })
+                                    .collect(),
+                            ))
+                            .build(),
+                    ]))
+                    .set_block_device_mappings(Some(vec![
+                        aws_sdk_ec2::types::LaunchTemplateBlockDeviceMappingRequest::builder()
+                            .device_name(plan.device_name.clone())
+                            .ebs(
+                                aws_sdk_ec2::types::LaunchTemplateEbsBlockDeviceRequest::builder()
+                                    .volume_size(plan.volume_size)
+                                    .volume_type(aws_sdk_ec2::types::VolumeType::Gp2)
+                                    .delete_on_termination(true)
+                                    .build(),
+                            )
+                            .build(),
+                    ]))
+                    .build(),
+            ))
+            .send()
+            .await
+            .map_err(classify)?;
+        output
+            .launch_template()
+            .and_then(|t| t.launch_template_id())
+            .map(|t| t.to_string())
+            .ok_or_else(|| incomplete("CreateLaunchTemplate returned no launch template id"))
+    }
+
+    async fn delete_launch_template(&self, id: &str) -> Result<(), CloudError> {
+        self.ec2_client
+            .delete_launch_template()
+            .launch_template_id(id)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(classify)
+    }
+
+    async fn create_auto_scaling_group(
+        &self,
+        plan: &AutoScalingGroupPlan,
+    ) -> Result<(), CloudError> {
+        self.as_client
+            .create_auto_scaling_group()
+            .auto_scaling_group_name(&plan.name)
+            .launch_template(
+                aws_sdk_autoscaling::types::LaunchTemplateSpecification::builder()
+                    .launch_template_name(&plan.launch_template_name)
+                    .build(),
+            )
+            .min_size(to_i32("min_size", plan.min_size)?)
+            .max_size(to_i32("max_size", plan.max_size)?)
+            .vpc_zone_identifier(plan.subnets.join(","))
+            .send()
+            .await
+            .map_err(classify)?;
+        Ok(())
+    }
+
+    async fn delete_auto_scaling_group(&self, name: &str) -> Result<(), CloudError> {
+        self.as_client
+            .delete_auto_scaling_group()
+            .auto_scaling_group_name(name)
+            .force_delete(true)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(classify)
+    }
+
+    async fn auto_scaling_group_exists(&self, name: &str) -> Result<bool, CloudError> {
+        let output = self
+            .as_client
+            .describe_auto_scaling_groups()
+            .auto_scaling_group_names(name)
+            .send()
+            .await
+            .map_err(classify)?;
+        Ok(!output.auto_scaling_groups().is_empty())
+    }
+
+    async fn auto_scaling_group_instances(&self, name: &str) -> Result<Vec<Instance>, CloudError> {
+        let output = self
+            .as_client
+            .describe_auto_scaling_groups()
+            .auto_scaling_group_names(name)
+            .send()
+            .await
+            .map_err(classify)?;
+        Ok(output
+            .auto_scaling_groups()
+            .iter()
+            .flat_map(|group| group.instances())
+            .map(|instance| Instance {
+                id: instance.instance_id().unwrap_or_default().to_string(),
+                lifecycle_state: instance
+                    .lifecycle_state()
+                    .map(|s| s.as_str().to_string())
+                    .unwrap_or_default(),
+                health_status: instance.health_status().unwrap_or_default().to_string(),
+            })
+            .collect())
+    }
+
+    async fn attach_target_groups(
+        &self,
+        auto_scaling_group: &str,
+        target_group_arns: &[String],
+    ) -> Result<(), CloudError> {
+        self.as_client
+            .attach_load_balancer_target_groups()
+            .auto_scaling_group_name(auto_scaling_group)
+            .set_target_group_arns(Some(target_group_arns.to_vec()))
+            .send()
+            .await
+            .map_err(classify)?;
+        Ok(())
+    }
+
+    async fn detach_target_groups(
+        &self,
+        auto_scaling_group: &str,
+        target_group_arns: &[String],
+    ) -> Result<(), CloudError> {
+        self.as_client
+            .detach_load_balancer_target_groups()
+            .auto_scaling_group_name(auto_scaling_group)
+            .set_target_group_arns(Some(target_group_arns.to_vec()))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(classify)
+    }
+
+    async fn create_target_group(&self, plan: &TargetGroupPlan) -> Result<String, CloudError> {
+        let output = self
+            .elb_client
+            .create_target_group()
+            .name(&plan.name)
+            .protocol(ProtocolEnum::from(plan.protocol.as_str()))
+            .port(to_i32("port", plan.port)?)
+            .vpc_id(&plan.vpc_id)
+            .set_health_check_path(plan.health_check_path.clone())
+            .set_health_check_enabled(plan.health_check_enabled)
+            .send()
+            .await
+            .map_err(classify)?;
+        output
+            .target_groups()
+            .first()
+            .and_then(|tg| tg.target_group_arn())
+            .map(|arn| arn.to_string())
+            .ok_or_else(|| incomplete("CreateTargetGroup returned no target group arn"))
+    }
+
+    async fn delete_target_group(&self, arn: &str) -> Result<(), CloudError> {
+        self.elb_client
+            .delete_target_group()
+            .target_group_arn(arn)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(classify)
+    }
+
+    async fn target_health(&self, arn: &str) -> Result<Vec<TargetHealth>, CloudError> {
+        let output = self
+            .elb_client
+            .describe_target_health()
+            .target_group_arn(arn)
+            .send()
+            .await
+            .map_err(classify)?;
+        Ok(output
+            .target_health_descriptions()
+            .iter()
+            .map(|description| {
+                let health = description.target_health();
+                TargetHealth {
+                    id: description
+                        .target()
+                        .and_then(|t| t.id())
+                        .unwrap_or_default()
+                        .to_string(),
+                    port: description.target().and_then(|t| t.port()).map(i64::from),
+                    state: health
+                        .and_then(|h| h.state())
+                        .map(|s| s.as_str().to_string())
+                        .unwrap_or_else(|| "unknown".to_string()),
+                    reason: health
+                        .and_then(|h| h.reason())
+                        .map(|r| r.as_str().to_string()),
+                    description: health.and_then(|h| h.description()).map(|d| d.to_string()),
+                }
+            })
+            .collect())
+    }
+
+    async fn create_security_group(&self, plan: &SecurityGroupPlan) -> Result<String, CloudError> {
+        let output = self
+            .ec2_client
+            .create_security_group()
+            .group_name(&plan.name)
+            .description(&plan.description)
+            .vpc_id(&plan.vpc_id)
+            .send()
+            .await
+            .map_err(classify)?;
+        output
+            .group_id()
+            .map(|id| id.to_string())
+            .ok_or_else(|| incomplete("CreateSecurityGroup returned no group id"))
+    }
+
+    async fn authorize_ingress(
+        &self,
+        group_id: &str,
+        rule: &IngressRulePlan,
+        source_group_id: Option<&str>,
+    ) -> Result<(), CloudError> {
+        let mut permission = IpPermission::builder()
+            .ip_protocol(&rule.protocol)
+            .from_port(to_i32("from_port", rule.from_port)?)
+            .to_port(to_i32("to_port", rule.to_port)?);
+        permission = match (&rule.source, source_group_id) {
+            (IngressSource::Cidr(cidr), _) if cidr.contains(':') => {
+                permission.ipv6_ranges(Ipv6Range::builder().cidr_ipv6(cidr).build())
+            }
+            (IngressSource::Cidr(cidr), _) => {
+                permission.ip_ranges(IpRange::builder().cidr_ip(cidr).build())
+            }
+            (IngressSource::SecurityGroup(_) | IngressSource::SecurityGroupId(_), Some(id)) => {
+                permission.user_id_group_pairs(UserIdGroupPair::builder().group_id(id).build())
+            }
+            (IngressSource::SecurityGroup(name) | IngressSource::SecurityGroupId(name), None) => {
+                return Err(CloudError::new(
+                    CloudErrorKind::InvalidRequest,
+                    format!("no id given for source security group {}", name),
+                ))
+            }
+        };
+        self.ec2_client
+            .authorize_security_group_ingress()
+            .group_id(group_id)
+            .ip_permissions(permission.build())
+            .send()
+            .await
+            .map_err(classify)?;
+        Ok(())
+    }
+
+    async fn delete_security_group(&self, group_id: &str) -> Result<(), CloudError> {
+        self.ec2_client
+            .delete_security_group()
+            .group_id(group_id)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(classify)
+    }
+
+    async fn subnet_is_public(&self, subnet_id: &str) -> Result<bool, CloudError> {
+        let explicit = self
+            .ec2_client
+            .describe_route_tables()
+            .filters(
+                Filter::builder()
+                    .name("association.subnet-id")
+                    .values(subnet_id)
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(classify)?;
+        let mut route_tables = explicit.route_tables().to_vec();
+        if route_tables.is_empty() {
+            // subnets without a route table of their own use the VPC's main one
+            let subnets = self
+                .ec2_client
+                .describe_subnets()
+                .subnet_ids(subnet_id)
+                .send()
+                .await
+                .map_err(classify)?;
+            let vpc_id = subnets
+                .subnets()
+                .first()
+                .and_then(|s| s.vpc_id())
+                .ok_or_else(|| incomplete("DescribeSubnets returned no subnet"))?;
+            let main = self
+                .ec2_client
+                .describe_route_tables()
+                .filters(Filter::builder().name("vpc-id").values(vpc_id).build())
+                .filters(
+                    Filter::builder()
+                        .name("association.main")
+                        .values("true")
+                        .build(),
+                )
+                .send()
+                .await
+                .map_err(classify)?;
+            route_tables = main.route_tables().to_vec();
+        }
+        Ok(route_tables.iter().flat_map(|t| t.routes()).any(|route| {
+            route
+                .gateway_id()
+                .map(|g| g.starts_with("igw-"))
+                .unwrap_or(false)
+        }))
+    }
+
+    async fn create_load_balancer(
+        &self,
+        plan: &LoadBalancerPlan,
+        security_group_id: &str,
+    ) -> Result<LoadBalancer, CloudError> {
+        let output = self
+            .elb_client
+            .create_load_balancer()
+            .name(&plan.name)
+            .scheme(LoadBalancerSchemeEnum::from(plan.scheme.as_str()))
+            .set_subnets(Some(plan.subnets.clone()))
+            .security_groups(security_group_id)
+            .send()
+            .await
+            .map_err(classify)?;
+        let load_balancer = output
+            .load_balancers()
+            .first()
+            .ok_or_else(|| incomplete("CreateLoadBalancer returned no load balancer"))?;
+        match (
+            load_balancer.load_balancer_arn(),
+            load_balancer.dns_name(),
+            load_balancer.canonical_hosted_zone_id(),
+        ) {
+            (Some(arn), Some(dns_name), Some(zone_id)) => Ok(LoadBalancer {
+                arn: arn.to_string(),
+                dns_name: dns_name.to_string(),
+                canonical_hosted_zone_id: zone_id.to_string(),
+            }),
+            _ => Err(incomplete(
+                "CreateLoadBalancer returned no arn, dns name or hosted zone",
+            )),
+        }
+    }
+
+    async fn load_balancer_state(
+        &self,
+        arn: &str,
+    ) -> Result<Option<LoadBalancerState>, CloudError> {
+        let resp = self
+            .elb_client
+            .describe_load_balancers()
+            .load_balancer_arns(arn)
+            .send()
+            .await;
+        match resp {
+            Ok(output) => Ok(output.load_balancers().first().map(|lb| LoadBalancerState {
+                code: lb
+                    .state()
+                    .and_then(|s| s.code())
+                    .map(|c| c.as_str().to_string())
+                    .unwrap_or_else(|| "unknown".to_string()),
+                reason: lb.state().and_then(|s| s.reason()).map(|r| r.to_string()),
+            })),
+            Err(e)
+                if e.as_service_error()
+                    .map(|e| e.is_load_balancer_not_found_exception())
+                    .unwrap_or(false) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(classify(e)),
+        }
+    }
+
+    async fn delete_load_balancer(&self, arn: &str) -> Result<(), CloudError> {
+        self.elb_client
+            .delete_load_balancer()
+            .load_balancer_arn(arn)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(classify)
+    }
+
+    async fn load_balancer(&self, arn: &str) -> Result<Option<LoadBalancer>, CloudError> {
+        let resp = self
+            .elb_client
+            .describe_load_balancers()
+            .load_balancer_arns(arn)
+            .send()
+            .await;
+        let output = match resp {
+            Ok(output) => output,
+            Err(e)
+                if e.as_service_error()
+                    .map(|e| e.is_load_balancer_not_found_exception())
+                    .unwrap_or(false) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(classify(e)),
+        };
+        let Some(load_balancer) = output.load_balancers().first() else {
+            return Ok(None);
+        };
+        match (
+            load_balancer.dns_name(),
+            load_balancer.canonical_hosted_zone_id(),
+        ) {
+            (Some(dns_name), Some(zone_id)) => Ok(Some(LoadBalancer {
+                arn: arn.to_string(),
+                dns_name: dns_name.to_string(),
+                canonical_hosted_zone_id: zone_id.to_string(),
+            })),
+            _ => Err(incomplete(
+                "DescribeLoadBalancers returned no dns name or hosted zone",
+            )),
+        }
+    }
+
+    async fn create_listener(
+        &self,
+        load_balancer_arn: &str,
+        plan: &ListenerPlan,
+        target_group_arn: Option<&str>,
+        certificate_arn: Option<&str>,
+    ) -> Result<String, CloudError> {
+        let action = match &plan.action {
+            ListenerAction::Forward(_) => Action::builder()
+                .r#type(ActionTypeEnum::Forward)
+                .set_target_group_arn(target_group_arn.map(|arn| arn.to_string()))
+                .build(),
+            ListenerAction::RedirectToHttps(port) => Action::builder()
+                .r#type(ActionTypeEnum::Redirect)
+                .redirect_config(
+                    RedirectActionConfig::builder()
+                        .protocol("HTTPS")
+                        .port(port.to_string())
+                        .status_code(RedirectActionStatusCodeEnum::Http301)
+                        .build(),
+                )
+                .build(),
+            ListenerAction::NotFound => Action::builder()
+                .r#type(ActionTypeEnum::FixedResponse)
+                .fixed_response_config(
+                    FixedResponseActionConfig::builder()
+                        .status_code("404")
+                        .content_type("text/plain")
+                        .message_body("Not Found")
+                        .build(),
+                )
+                .build(),
+        };
+        let output = self
+            .elb_client
+            .create_listener()
+            .load_balancer_arn(load_balancer_arn)
+            .port(to_i32("port", plan.port)?)
+            .protocol(ProtocolEnum::from(plan.protocol.as_str()))
+            .set_certificates(
+                certificate_arn
+                    .map(|arn| vec![ListenerCertificate::builder().certificate_arn(arn).build()]),
+            )
+            .default_actions(action)
+            .send()
+            .await
+            .map_err(classify)?;
+        output
+            .listeners()
+            .first()
+            .and_then(|l| l.listener_arn())
+            .map(|arn| arn.to_string())
+            .ok_or_else(|| incomplete("CreateListener returned no listener arn"))
+    }
+
+    async fn delete_listener(&self, arn: &str) -> Result<(), CloudError> {
+        self.elb_client
+            .delete_listener()
+            .listener_arn(arn)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(classify)
+    }
+
+    async fn listener_rule_priorities(&self, listener_arn: &str) -> Result<Vec<i64>, CloudError> {
+        let mut priorities = vec![];
+        let mut marker = None;
+        loop {
+            let output = self
+                .elb_client
+                .describe_rules()
+                .listener_arn(listener_arn)
+                .set_marker(marker)
+                .send()
+                .await
+                .map_err(classify)?;
+            // the default rule's priority is `default`
+            priorities.extend(
+                output
+                    .rules()
+                    .iter()
+                    .filter_map(|r| r.priority())
+                    .filter_map(|p| p.parse::<i64>().ok()),
+            );
+            marker = output.next_marker().map(|m| m.to_string());
+            if marker.is_none() {
+                return Ok(priorities);
+            }
+        }
+    }
+
+    async fn create_listener_rule(
+        &self,
+        listener_arn: &str,
+        plan: &ListenerRulePlan,
+        priority: i64,
+        target_group_arn: &str,
+    ) -> Result<String, CloudError> {
+        let mut conditions = vec![];
+        if let Some(host_header) = &plan.host_header {
+            conditions.push(
+                RuleCondition::builder()
+                    .field("host-header")
+                    .host_header_config(
+                        HostHeaderConditionConfig::builder()
+                            .values(host_header)
+                            .build(),
+                    )
+                    .build(),
+            );
+        }
+        if let Some(path_pattern) = &plan.path_pattern {
+            conditions.push(
+                RuleCondition::builder()
+                    .field("path-pattern")
+                    .path_pattern_config(
+                        PathPatternConditionConfig::builder()
+                            .values(path_pattern)
+                            .build(),
+                    )
+                    .build(),
+            );
+        }
+        let output = self
+            .elb_client
+            .create_rule()
+            .listener_arn(listener_arn)
+            .priority(to_i32("priority", priority)?)
+            .set_conditions(Some(conditions))
+            .actions(
+                Action::builder()
+                    .r#type(ActionTypeEnum::Forward)
+                    .target_group_arn(target_group_arn)
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(classify)?;
+        output
+            .rules()
+            .first()
+            .and_then(|r| r.rule_arn())
+            .map(|arn| arn.to_string())
+            .ok_or_else(|| incomplete("CreateRule returned no rule arn"))
+    }
+
+    async fn delete_listener_rule(&self, arn: &str) -> Result<(), CloudError> {
+        self.elb_client
+            .delete_rule()
+            .rule_arn(arn)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(classify)
+    }
+
+    async fn request_certificate(&self, plan: &CertificatePlan) -> Result<String, CloudError> {
+        let output = self
+            .acm_client
+            .request_certificate()
+            .domain_name(&plan.domain_name)
+            .validation_method(ValidationMethod::Dns)
+            .send()
+            .await
+            .map_err(classify)?;
+        output
+            .certificate_arn()
+            .map(|arn| arn.to_string())
+            .ok_or_else(|| incomplete("RequestCertificate returned no certificate arn"))
+    }
+
+    async fn certificate(&self, arn: &str) -> Result<Certificate, CloudError> {
+        let output = self
+            .acm_client
+            .describe_certificate()
+            .certificate_arn(arn)
+            .send()
+            .await
+            .map_err(classify)?;
+        let detail = output
+            .certificate()
+            .ok_or_else(|| incomplete("DescribeCertificate returned no certificate"))?;
+        Ok(Certificate {
+            status: detail
+                .status()
+                .map(|s| s.as_str().to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            validation_records: detail
+                .domain_validation_options()
+                .iter()
+                .filter_map(|v| v.resource_record())
+                .map(|r| ValidationRecord {
+                    name: r.name().to_string(),
+                    record_type: r.r#type().as_str().to_string(),
+                    value: r.value().to_string(),
+                })
+                .collect(),
+        })
+    }
+
+    async fn delete_certificate(&self, arn: &str) -> Result<(), CloudError> {
+        self.acm_client
+            .delete_certificate()
+            .certificate_arn(arn)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(classify)
+    }
+
+    async fn hosted_zone_name(&self, hosted_zone_id: &str) -> Result<String, CloudError> {
+        let output = self
+            .route53_client
+            .get_hosted_zone()
+            .id(hosted_zone_id)
+            .send()
+            .await
+            .map_err(classify)?;
+        output
+            .hosted_zone()
+            .map(|z| z.name().trim_end_matches('.').to_string())
+            .ok_or_else(|| incomplete("GetHostedZone returned no hosted zone"))
+    }
+
+    async fn record_set_change_status(&self, change_id: &str) -> Result<String, CloudError> {
+        let output = self
+            .route53_client
+            .get_change()
+            .id(change_id)
+            .send()
+            .await
+            .map_err(classify)?;
+        output
+            .change_info()
+            .map(|c| c.status().as_str().to_string())
+            .ok_or_else(|| incomplete("GetChange returned no change info"))
+    }
+
+    async fn change_record_set(
+        &self,
+        action: &str,
+        record_set: &RecordSet,
+    ) -> Result<String, CloudError> {
+        let mut resource_record_set = ResourceRecordSet::builder()
+            .name(&record_set.name)
+            .r#type(RrType::from(record_set.record_type.as_str()))
+            .set_ttl(record_set.ttl);
+        if let Some(value) = &record_set.value {
+            resource_record_set = resource_record_set.resource_records(
+                ResourceRecord::builder()
+                    .value(value)
+                    .build()
+                    .map_err(invalid)?,
+            );
+        }
+        if let Some(target) = &record_set.alias_target {
+            resource_record_set = resource_record_set.alias_target(
+                AliasTarget::builder()
+                    .hosted_zone_id(&target.hosted_zone_id)
+                    .dns_name(&target.dns_name)
+                    .evaluate_target_health(false)
+                    .build()
+                    .map_err(invalid)?,
+            );
+        }
+        let resource_record_set = resource_record_set.build().map_err(invalid)?;
+        let action = ChangeAction::from(action);
+        let change_batch = ChangeBatch::builder()
+            .changes(
+                Change::builder()
+                    .action(action.clone())
+                    .resource_record_set(resource_record_set)
+                    .build()
+                    .map_err(invalid)?,
+            )
+            .build()
+            .map_err(invalid)?;
+        let output = self
+            .route53_client
+            .change_resource_record_sets()
+            .hosted_zone_id(&record_set.hosted_zone_id)
+            .change_batch(change_batch)
+            .send()
+            .await
+            .map_err(|e| {
+                let mut error = classify(e);
+                // deleting a record that is already gone, or that has since
+                // been replaced, is an invalid batch; either way the record we
+                // created no longer exists. Any other change rejected this way
+                // is a genuine error
+                if action == ChangeAction::Delete
+                    && error.code.as_deref() == Some("InvalidChangeBatch")
+                    && (error.message.contains("not found")
+                        || error.message.contains("do not match"))
+                {
+                    error.kind = CloudErrorKind::NotFound;
+                }
+                error
+            })?;
+        output
+            .change_info()
+            .map(|c| c.id().to_string())
+            .ok_or_else(|| incomplete("ChangeResourceRecordSets returned no change info"))
+    }
+}
@@ -0,0 +1,224 @@
+use async_trait::async_trait;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::okapi::schemars::JsonSchema;
+
+use crate::deployment::RecordSet;
+use crate::error::CloudError;
+use crate::plan::{
+    AutoScalingGroupPlan, CertificatePlan, IngressRulePlan, LaunchTemplatePlan, ListenerPlan,
+    ListenerRulePlan, LoadBalancerPlan, SecurityGroupPlan, TargetGroupPlan,
+};
+use crate::secrets::SecretStore;
+
+/// A load balancer as returned when it is created or described
+#[derive(Clone, Debug)]
+pub struct LoadBalancer {
+    pub arn: String,
+    pub dns_name: String,
+    /// The zone alias records pointing at the load balancer refer to
+    pub canonical_hosted_zone_id: String,
+}
+
+/// The state of a load balancer, e.g. `provisioning`, `active` or `failed`
+#[derive(Clone, Debug)]
+pub struct LoadBalancerState {
+    pub code: String,
+    /// Why the load balancer is in this state, if the provider says
+    pub reason: Option<String>,
+}
+
+impl std::fmt::Display for LoadBalancerState {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.reason {
+            Some(reason) => write!(formatter, "{} ({})", self.code, reason),
+            None => write!(formatter, "{}", self.code),
+        }
+    }
+}
+
+/// An instance of an auto scaling group
+#[derive(Clone, Debug)]
+pub struct Instance {
+    pub id: String,
+    /// e.g. `Pending` or `InService`
+    pub lifecycle_state: String,
+    /// `Healthy` or `Unhealthy`
+    pub health_status: String,
+}
+
+impl Instance {
+    pub fn in_service(&self) -> bool {
+        self.lifecycle_state == "InService" && self.health_status == "Healthy"
+    }
+}
+
+/// The health of one target of a target group, as its health checks see it
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct TargetHealth {
+    /// The instance id
+    pub id: String,
+    pub port: Option<i64>,
+    /// e.g. `initial`, `healthy`, `unhealthy` or `draining`
+    pub state: String,
+    /// Why the target is not healthy, e.g. `Target.FailedHealthChecks`
+    pub reason: Option<String>,
+    pub description: Option<String>,
+}
+
+impl TargetHealth {
+    /// Whether the target passes its health checks, or has none to pass as
+    /// its target group has them disabled, which ELB reports as `unavailable`
+    pub fn healthy(&self) -> bool {
+        self.state == "healthy"
+            || (self.state == "unavailable"
+                && self.reason.as_deref() == Some("Target.HealthCheckDisabled"))
+    }
+}
+
+/// A record that has to exist in DNS for a certificate to be issued
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationRecord {
+    pub name: String,
+    pub record_type: String,
+    pub value: String,
+}
+
+/// A requested certificate
+#[derive(Clone, Debug)]
+pub struct Certificate {
+    /// e.g. `PENDING_VALIDATION`, `ISSUED` or `FAILED`
+    pub status: String,
+    /// Empty until the provider has generated them
+    pub validation_records: Vec<ValidationRecord>,
+}
+
+impl Certificate {
+    pub fn issued(&self) -> bool {
+        self.status == "ISSUED"
+    }
+
+    /// Whether the certificate will never be issued
+    pub fn failed(&self) -> bool {
+        matches!(
+            self.status.as_str(),
+            "FAILED" | "VALIDATION_TIMED_OUT" | "REVOKED" | "EXPIRED"
+        )
+    }
+}
+
+/// Everything a deployment needs from a cloud, one call per resource
+///
+/// Provisioning, rollback and teardown only go through this trait, so they
+/// can run against AWS or against the in-memory fake used by the tests.
+/// Errors are classified so callers can tell e.g. throttling from a name
+/// conflict.
+#[async_trait]
+pub trait CloudProvider: Send + Sync {
+    /// Where deployment secrets are kept
+    fn secrets(&self) -> &dyn SecretStore;
+
+    /// Returns the launch template id; instances get the security group
+    /// `security_group_id` and the base64 encoded `user_data`
+    async fn create_launch_template(
+        &self,
+        plan: &LaunchTemplatePlan,
+        security_group_id: &str,
+        user_data: Option<&str>,
+    ) -> Result<String, CloudError>;
+    async fn delete_launch_template(&self, id: &str) -> Result<(), CloudError>;
+
+    async fn create_auto_scaling_group(
+        &self,
+        plan: &AutoScalingGroupPlan,
+    ) -> Result<(), CloudError>;
+    /// Delete the group without waiting for its instances to terminate
+    async fn delete_auto_scaling_group(&self, name: &str) -> Result<(), CloudError>;
+    /// Whether the group still exists; it does until its instances are gone
+    async fn auto_scaling_group_exists(&self, name: &str) -> Result<bool, CloudError>;
+    async fn auto_scaling_group_instances(&self, name: &str) -> Result<Vec<Instance>, CloudError>;
+    async fn attach_target_groups(
+        &self,
+        auto_scaling_group: &str,
+        target_group_arns: &[String],
+    ) -> Result<(), CloudError>;
+    async fn detach_target_groups(
+        &self,
+        auto_scaling_group: &str,
+        target_group_arns: &[String],
+    ) -> Result<(), CloudError>;
+
+    /// Returns the target group ARN
+    async fn create_target_group(&self, plan: &TargetGroupPlan) -> Result<String, CloudError>;
+    async fn delete_target_group(&self, arn: &str) -> Result<(), CloudError>;
+    async fn target_health(&self, arn: &str) -> Result<Vec<TargetHealth>, CloudError>;
+
+    /// Returns the security group id; ingress is authorized separately
+    async fn create_security_group(&self, plan: &SecurityGroupPlan) -> Result<String, CloudError>;
+    /// `source_group_id` is set for rules allowing another security group
+    async fn authorize_ingress(
+        &self,
+        group_id: &str,
+        rule: &IngressRulePlan,
+        source_group_id: Option<&str>,
+    ) -> Result<(), CloudError>;
+    /// Fails while the group is in use, or other groups' rules refer to it
+    async fn delete_security_group(&self, group_id: &str) -> Result<(), CloudError>;
+
+    /// Whether the subnet routes to an internet gateway, explicitly or
+    /// through its VPC's main route table
+    async fn subnet_is_public(&self, subnet_id: &str) -> Result<bool, CloudError>;
+
+    async fn create_load_balancer(
+        &self,
+        plan: &LoadBalancerPlan,
+        security_group_id: &str,
+    ) -> Result<LoadBalancer, CloudError>;
+    /// The load balancer's state, or `None` once it no longer exists
+    async fn load_balancer_state(&self, arn: &str)
+        -> Result<Option<LoadBalancerState>, CloudError>;
+    /// Start deleting the load balancer; it disappears some time later
+    async fn delete_load_balancer(&self, arn: &str) -> Result<(), CloudError>;
+    /// Describe an existing load balancer, or `None` if there is none
+    async fn load_balancer(&self, arn: &str) -> Result<Option<LoadBalancer>, CloudError>;
+
+    /// Returns the listener ARN; `target_group_arn` is set for listeners
+    /// that forward, and `certificate_arn` for HTTPS listeners
+    async fn create_listener(
+        &self,
+        load_balancer_arn: &str,
+        plan: &ListenerPlan,
+        target_group_arn: Option<&str>,
+        certificate_arn: Option<&str>,
+    ) -> Result<String, CloudError>;
+    async fn delete_listener(&self, arn: &str) -> Result<(), CloudError>;
+    /// The priorities taken by the listener's rules, not counting its default
+    async fn listener_rule_priorities(&self, listener_arn: &str) -> Result<Vec<i64>, CloudError>;
+    /// Returns the rule ARN; fails with `PriorityInUse` if another rule of
+    /// the listener already has `priority`
+    async fn create_listener_rule(
+        &self,
+        listener_arn: &str,
+        plan: &ListenerRulePlan,
+        priority: i64,
+        target_group_arn: &str,
+    ) -> Result<String, CloudError>;
+    async fn delete_listener_rule(&self, arn: &str) -> Result<(), CloudError>;
+
+    /// Request a DNS validated certificate, returning its ARN
+    async fn request_certificate(&self, plan: &CertificatePlan) -> Result<String, CloudError>;
+    async fn certificate(&self, arn: &str) -> Result<Certificate, CloudError>;
+    /// Fails while a listener still uses the certificate
+    async fn delete_certificate(&self, arn: &str) -> Result<(), CloudError>;
+
+    /// The domain name of the hosted zone, without the trailing dot
+    async fn hosted_zone_name(&self, hosted_zone_id: &str) -> Result<String, CloudError>;
+    /// The status of a record set change, `PENDING` or `INSYNC`
+    async fn record_set_change_status(&self, change_id: &str) -> Result<String, CloudError>;
+    /// Apply `action` (`CREATE`, `UPSERT` or `DELETE`) to the record, returning the
+    /// change id
+    async fn change_record_set(
+        &self,
+        action: &str,
+        record_set: &RecordSet,
+    ) -> Result<String, CloudError>;
+}
@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+
+use aws_sdk_ec2::types::InstanceType;
+use rocket::figment::providers::Env;
+use rocket::figment::Figment;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::okapi::schemars::JsonSchema;
+
+use crate::error;
+use crate::plan::Scheme;
+use crate::validation;
+
+/// Instance types an environment allows unless it lists its own
+pub const DEFAULT_ALLOWED_INSTANCE_TYPES: [&str; 4] =
+    ["t3.micro", "t3.small", "t3.medium", "t3.large"];
+
+fn default_allowed_instance_types() -> Vec<String> {
+    DEFAULT_ALLOWED_INSTANCE_TYPES
+        .iter()
+        .map(|t| t.to_string())
+        .collect()
+}
+
+/// Where deployment secrets are written
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretsBackend {
+    /// SSM Parameter Store SecureString parameters
+    #[default]
+    Ssm,
+    SecretsManager,
+    /// In memory, or files under `dir`; instances can't read these
+    Local,
+}
+
+impl SecretsBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecretsBackend::Ssm => "ssm",
+            SecretsBackend::SecretsManager => "secrets_manager",
+            SecretsBackend::Local => "local",
+        }
+    }
+}
+
+fn default_secrets_prefix() -> String {
+    "/flakery".to_string()
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SecretsConfig {
+    #[serde(default)]
+    pub backend: SecretsBackend,
+    /// Secrets of a deployment are written under `<prefix>/<deployment_slug>`
+    #[serde(default = "default_secrets_prefix")]
+    pub prefix: String,
+    /// Directory the `local` backend writes to
+    pub dir: Option<String>,
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        Self {
+            backend: SecretsBackend::default(),
+            prefix: default_secrets_prefix(),
+            dir: None,
+        }
+    }
+}
+
+/// How the AWS clients of an environment connect and authenticate
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct AwsSettings {
+    /// Named profile from the shared AWS config and credentials files; the
+    /// default credential chain is used when unset
+    pub profile: Option<String>,
+    /// Send every AWS request here instead of to the regional endpoints,
+    /// e.g. a local emulator such as LocalStack or moto
+    pub endpoint_url: Option<String>,
+    /// Static credentials, such as an emulator's test credentials; they
+    /// take precedence over `profile`
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+}
+
+/// How failed AWS calls made while provisioning are retried
+///
+/// Throttling, server errors and timeouts are retried, as are "not found"
+/// errors for resources that were only just created. This is on top of the
+/// SDK's own retries.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Attempts per call, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry in milliseconds, doubled for every
+    /// further retry
+    pub base_delay_ms: u64,
+    /// Longest delay between two attempts in milliseconds
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay_ms: 500,
+            max_delay_ms: 20_000,
+        }
+    }
+}
+
+/// How long to wait for a resource to reach a state, and how often to check
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+pub struct WaitConfig {
+    pub timeout_ms: u64,
+    pub poll_interval_ms: u64,
+}
+
+impl WaitConfig {
+    const fn new(timeout_secs: u64, poll_interval_secs: u64) -> Self {
+        Self {
+            timeout_ms: timeout_secs * 1000,
+            poll_interval_ms: poll_interval_secs * 1000,
+        }
+    }
+}
+
+/// The waits of provisioning and teardown
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct WaitsConfig {
+    /// For the load balancer to become active, and to disappear once deleted
+    pub load_balancer: WaitConfig,
+    /// For `min_size` instances of the auto scaling group to be in service
+    pub instances: WaitConfig,
+    /// For a deleted auto scaling group's instances to terminate
+    pub auto_scaling_group_deletion: WaitConfig,
+    /// For targets to pass their health checks, with `wait_for_healthy`
+    pub targets: WaitConfig,
+    /// For the Route53 change to be in sync, with `wait_for_healthy`
+    pub record_set: WaitConfig,
+    /// For a certificate to be validated and issued, with `https`
+    pub certificate: WaitConfig,
+}
+
+impl Default for WaitsConfig {
+    fn default() -> Self {
+        Self {
+            load_balancer: WaitConfig::new(300, 3),
+            instances: WaitConfig::new(600, 5),
+            auto_scaling_group_deletion: WaitConfig::new(600, 5),
+            targets: WaitConfig::new(900, 10),
+            record_set: WaitConfig::new(300, 5),
+            certificate: WaitConfig::new(1800, 10),
+        }
+    }
+}
+
+fn default_ingress_cidrs() -> Vec<String> {
+    vec!["0.0.0.0/0".to_string()]
+}
+
+/// A load balancer provisioned ahead of time and shared by the deployments
+/// that ask for it, each routed to by a rule matching its host name
+///
+/// Its listener must serve a wildcard certificate for the hosted zone, e.g.
+/// `*.example.com`, which covers every deployment's name.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SharedLoadBalancerConfig {
+    pub arn: String,
+    /// The HTTPS listener deployments add their rules to
+    pub listener_arn: String,
+    /// The load balancer's security group, which deployments' instances admit
+    pub security_group_id: String,
+    #[serde(default)]
+    pub scheme: Scheme,
+}
+
+/// Whether `region` looks like an AWS region name, e.g. `us-west-1`
+fn valid_region(region: &str) -> bool {
+    let parts = region.split('-').collect::<Vec<&str>>();
+    let Some((number, names)) = parts.split_last() else {
+        return false;
+    };
+    names.len() >= 2
+        && !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit())
+        && names
+            .iter()
+            .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_lowercase()))
+}
+
+/// Where and how deployments of one environment (e.g. `staging`, `prod`) are
+/// provisioned
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct Environment {
+    pub region: String,
+    pub vpc_id: String,
+    /// Subnets the auto scaling group launches instances into
+    pub instance_subnets: Vec<String>,
+    /// Subnets internet-facing load balancers are placed in
+    pub public_subnets: Vec<String>,
+    /// Subnets internal load balancers are placed in; internal deployments
+    /// are only possible with these and `private_hosted_zone_id`
+    #[serde(default)]
+    pub private_subnets: Vec<String>,
+    /// Where clients may reach load balancers from, `0.0.0.0/0` by default;
+    /// load balancers are IPv4 only
+    #[serde(default = "default_ingress_cidrs")]
+    pub ingress_cidrs: Vec<String>,
+    /// The public zone, which also holds certificate validation records
+    pub hosted_zone_id: String,
+    /// The zone of internal deployments' records
+    pub private_hosted_zone_id: Option<String>,
+    /// Used by deployments with `shared_load_balancer` instead of their own
+    pub shared_load_balancer: Option<SharedLoadBalancerConfig>,
+    pub image_id: String,
+    #[serde(default = "default_allowed_instance_types")]
+    pub allowed_instance_types: Vec<String>,
+    /// Name or ARN of the IAM instance profile instances run with; its role
+    /// must be allowed to read the deployment secrets
+    ///
+    /// Every deployment of the environment runs with this one profile, so its
+    /// instances can read the secrets of every other deployment under the
+    /// secrets `prefix`: secrets are kept out of the launch template and
+    /// instance tags, but are not isolated between deployments.
+    pub instance_profile: Option<String>,
+    #[serde(default)]
+    pub secrets: SecretsConfig,
+    #[serde(default)]
+    pub aws: AwsSettings,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub waits: WaitsConfig,
+}
+
+impl Environment {
+    fn validate(&self, name: &str) -> Result<(), String> {
+        let required = [
+            ("region", self.region.is_empty()),
+            ("vpc_id", self.vpc_id.is_empty()),
+            ("instance_subnets", self.instance_subnets.is_empty()),
+            ("public_subnets", self.public_subnets.is_empty()),
+            ("ingress_cidrs", self.ingress_cidrs.is_empty()),
+            ("hosted_zone_id", self.hosted_zone_id.is_empty()),
+            ("image_id", self.image_id.is_empty()),
+            (
+                "allowed_instance_types",
+                self.allowed_instance_types.is_empty(),
+            ),
+        ];
+        for (field, missing) in required {
+            if missing {
+                return Err(format!("environment {} is missing {}", name, field));
+            }
+        }
+        if let Some(shared) = &self.shared_load_balancer {
+            for (field, value) in [
+                ("arn", &shared.arn),
+                ("listener_arn", &shared.listener_arn),
+                ("security_group_id", &shared.security_group_id),
+            ] {
+                if value.is_empty() {
+                    return Err(format!(
+                        "environment {} is missing shared_load_balancer.{}",
+                        name, field
+                    ));
+                }
+            }
+        }
+        if !valid_region(&self.region) {
+            return Err(format!(
+                "environment {} has unknown region {}",
+                name, self.region
+            ));
+        }
+        if self.aws.access_key_id.is_some() != self.aws.secret_access_key.is_some() {
+            return Err(format!(
+                "environment {} must set both aws.access_key_id and aws.secret_access_key, or neither",
+                name
+            ));
+        }
+        if self.retry.max_attempts == 0 || self.retry.base_delay_ms > self.retry.max_delay_ms {
+            return Err(format!(
+                "environment {} needs retry.max_attempts of at least 1 and retry.base_delay_ms no greater than retry.max_delay_ms",
+                name
+            ));
+        }
+        for (wait, config) in [
+            ("load_balancer", self.waits.load_balancer),
+            ("instances", self.waits.instances),
+            (
+                "auto_scaling_group_deletion",
+                self.waits.auto_scaling_group_deletion,
+            ),
+            ("targets", self.waits.targets),
+            ("record_set", self.waits.record_set),
+            ("certificate", self.waits.certificate),
+        ] {
+            if config.poll_interval_ms == 0 || config.poll_interval_ms > config.timeout_ms {
+                return Err(format!(
+                    "environment {} needs a waits.{}.poll_interval_ms between 1 and its timeout_ms",
+                    name, wait
+                ));
+            }
+        }
+        if !self.secrets.prefix.starts_with('/') {
+            return Err(format!(
+                "environment {} has secrets prefix {}, which must start with /",
+                name, self.secrets.prefix
+            ));
+        }
+        if self.secrets.backend != SecretsBackend::Local && self.instance_profile.is_none() {
+            return Err(format!(
+                "environment {} is missing instance_profile, which instances need to read their secrets",
+                name
+            ));
+        }
+        if let Some(subnet) = self
+            .private_subnets
+            .iter()
+            .find(|s| self.public_subnets.contains(s))
+        {
+            return Err(format!(
+                "environment {} lists subnet {} as both public and private",
+                name, subnet
+            ));
+        }
+        if let Some(cidr) = self
+            .ingress_cidrs
+            .iter()
+            .find(|c| !validation::valid_cidr(c))
+        {
+            return Err(format!(
+                "environment {} has ingress CIDR {}, which is not a CIDR block",
+                name, cidr
+            ));
+        }
+        for instance_type in &self.allowed_instance_types {
+            if !InstanceType::values().contains(&instance_type.as_str()) {
+                return Err(format!(
+                    "environment {} allows unknown instance type {}",
+                    name, instance_type
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Service configuration
+///
+/// Read from the same figment as Rocket itself (`Rocket.toml` and `ROCKET_`
+/// environment variables), with `APP_` environment variables layered on top.
+/// Nested keys are separated by `__`, e.g.
+/// `APP_ENVIRONMENTS__PROD__IMAGE_ID=ami-123`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// The environment used when a request doesn't name one
+    pub default_environment: String,
+    pub environments: HashMap<String, Environment>,
+}
+
+impl Config {
+    pub fn figment() -> Figment {
+        rocket::Config::figment().merge(Env::prefixed("APP_").split("__").global())
+    }
+
+    /// Extract the configuration from `figment` and check that every
+    /// environment is complete
+    pub fn load(figment: &Figment) -> Result<Self, String> {
+        let config: Config = figment.extract().map_err(|e| e.to_string())?;
+        if config.environments.is_empty() {
+            return Err("no environments are configured".to_string());
+        }
+        if !config
+            .environments
+            .contains_key(&config.default_environment)
+        {
+            return Err(format!(
+                "default environment {} is not configured",
+                config.default_environment
+            ));
+        }
+        for (name, environment) in &config.environments {
+            environment.validate(name)?;
+        }
+        Ok(config)
+    }
+
+    /// Resolve the environment a request asked for, falling back to the default
+    pub fn environment(&self, name: Option<&str>) -> Result<(String, &Environment), error::Error> {
+        let name = name.unwrap_or(&self.default_environment);
+        let environment = self.environments.get(name).ok_or_else(|| {
+            let mut known = self.environments.keys().cloned().collect::<Vec<String>>();
+            known.sort();
+            error::Error::new(
+                "UnknownEnvironment",
+                Some(&format!(
+                    "environment {} is not configured, expected one of: {}",
+                    name,
+                    known.join(", ")
+                )),
+                422,
+            )
+        })?;
+        Ok((name.to_string(), environment))
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::okapi::schemars::JsonSchema;
+
+use crate::cloud::TargetHealth;
+use crate::plan::Plan;
+use crate::teardown::ResourceTeardown;
+use crate::{DeployAWSInput, StoredFile};
+
+/// How far provisioning of a deployment has progressed
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeploymentPhase {
+    /// Waiting for a worker to pick the deployment up
+    Pending,
+    SecretsStored,
+    /// The certificate was requested and its validation records created;
+    /// only with `https`
+    CertificateRequested,
+    SecurityGroupsCreated,
+    LaunchTemplateCreated,
+    AutoScalingGroupCreated,
+    TargetGroupsCreated,
+    LoadBalancerCreated,
+    LoadBalancerActive,
+    /// Only with `https`
+    CertificateIssued,
+    ListenersCreated,
+    /// The deployment is routed to from the shared load balancer; only with
+    /// `shared_load_balancer`
+    ListenerRuleCreated,
+    TargetGroupsAttached,
+    /// At least `min_size` instances are running and healthy
+    InstancesInService,
+    /// At least `min_size` targets pass the health checks of every target
+    /// group; only with `wait_for_healthy`
+    TargetsHealthy,
+    /// The record set change has reached every Route53 server; only with
+    /// `wait_for_healthy`
+    RecordSetInSync,
+    Ready,
+    Failed,
+    /// Provisioning failed and every resource created so far was deleted
+    RolledBack,
+    Deleting,
+    Deleted,
+}
+
+impl DeploymentPhase {
+    /// Whether a worker was part way through provisioning the deployment
+    pub fn is_provisioning(&self) -> bool {
+        !matches!(
+            self,
+            DeploymentPhase::Pending
+                | DeploymentPhase::Ready
+                | DeploymentPhase::Failed
+                | DeploymentPhase::RolledBack
+                | DeploymentPhase::Deleting
+                | DeploymentPhase::Deleted
+        )
+    }
+}
+
+/// A Route53 record: an alias pointing the deployment's name at the load
+/// balancer, or one validating a certificate
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct RecordSet {
+    pub hosted_zone_id: String,
+    /// Fully qualified, e.g. `app.example.com`
+    pub name: String,
+    pub record_type: String,
+    pub ttl: Option<i64>,
+    /// Unset for alias records
+    pub value: Option<String>,
+    pub alias_target: Option<AliasTarget>,
+    pub change_id: Option<String>,
+}
+
+/// The AWS resource an alias record resolves to
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct AliasTarget {
+    /// The canonical hosted zone of the resource, not the record's zone
+    pub hosted_zone_id: String,
+    pub dns_name: String,
+}
+
+impl RecordSet {
+    /// Whether this is the record `other` changed
+    pub fn same_record(&self, other: &RecordSet) -> bool {
+        self.name == other.name && self.record_type == other.record_type
+    }
+}
+
+/// Every AWS resource created for a deployment so far
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct DeploymentResources {
+    /// Where the deployment's secrets were written
+    pub secrets_reference: Option<String>,
+    pub launch_template_id: Option<String>,
+    pub auto_scaling_group_name: Option<String>,
+    pub target_group_arns: Vec<String>,
+    /// Unset on the shared load balancer, whose group is the environment's
+    pub load_balancer_security_group_id: Option<String>,
+    #[serde(default)]
+    pub instance_security_group_id: Option<String>,
+    pub load_balancer_arn: Option<String>,
+    pub load_balancer_dns: Option<String>,
+    pub listener_arns: Vec<String>,
+    /// Rules routing to the deployment by path or host name, on its own
+    /// listener or the shared load balancer's
+    #[serde(default)]
+    pub listener_rule_arns: Vec<String>,
+    /// The aliases of the deployment's name
+    #[serde(default)]
+    pub record_sets: Vec<RecordSet>,
+    #[serde(default)]
+    pub certificate_arn: Option<String>,
+    /// The records proving the certificate's domain is ours; these are left in
+    /// place when the deployment is deleted, as every certificate for the
+    /// domain validates with the same record
+    #[serde(default)]
+    pub validation_record_sets: Vec<RecordSet>,
+}
+
+/// A deployment as it is persisted in the deployment store
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct DeploymentRecord {
+    pub id: String,
+    pub input: DeployAWSInput<StoredFile>,
+    pub phase: DeploymentPhase,
+    /// The resources the deployment was planned to create
+    pub plan: Plan,
+    pub resources: DeploymentResources,
+    /// Unix timestamp (seconds) of when the deployment was requested
+    pub created_at: u64,
+    /// Set when the deployment failed
+    pub error: Option<String>,
+    /// The stable code of `error`, e.g. `CloudThrottled`
+    #[serde(default)]
+    pub error_code: Option<String>,
+    /// Seconds to wait before requesting the deployment again, when `error`
+    /// was a cloud failure that may pass, such as throttling
+    #[serde(default)]
+    pub retry_after: Option<u64>,
+    /// The health of every target, by target group ARN, as last seen while
+    /// waiting for them to become healthy
+    #[serde(default)]
+    pub target_health: BTreeMap<String, Vec<TargetHealth>>,
+    /// How often each cloud operation was retried, e.g. `create_target_group`
+    #[serde(default)]
+    pub retries: BTreeMap<String, u32>,
+    /// The result of rolling back the resources created before the failure
+    pub rollback: Option<Vec<ResourceTeardown>>,
+    /// The result of deleting each resource, in the order they were deleted,
+    /// once `DELETE /deploy/aws/<id>` has run
+    #[serde(default)]
+    pub teardown: Option<Vec<ResourceTeardown>>,
+}
+
+impl DeploymentRecord {
+    pub fn new(id: String, input: DeployAWSInput<StoredFile>, plan: Plan) -> Self {
+        Self {
+            id,
+            input,
+            phase: DeploymentPhase::Pending,
+            plan,
+            resources: DeploymentResources::default(),
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            error: None,
+            error_code: None,
+            retry_after: None,
+            target_health: BTreeMap::new(),
+            retries: BTreeMap::new(),
+            rollback: None,
+            teardown: None,
+        }
+    }
+}
@@ -14,25 +14,73 @@ pub struct Error {
     pub err: String,
     /// The description of the error
     pub msg: Option<String>,
+    /// Every invalid field of the request, when the request failed validation
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    /// The id AWS gave the failed request, for AWS support
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Seconds the client should wait before retrying, sent as `Retry-After`
+    #[serde(skip)]
+    pub retry_after: Option<u64>,
     // HTTP Status Code returned
     #[serde(skip)]
     pub http_status_code: u16,
 }
 
+/// Why a single field of a request is invalid
+#[derive(Debug, Clone, serde::Serialize, schemars::JsonSchema)]
+pub struct FieldError {
+    /// Path of the field, e.g. `targets[1].port`
+    pub field: String,
+    /// The title of the problem, e.g. `PortOutOfRange`
+    pub code: String,
+    /// The description of the problem
+    pub message: String,
+}
+
 impl Error {
     /// Create a new error message
     pub fn new(err: &str, msg: Option<&str>, http_status_code: u16) -> Self {
         Self {
             err: err.to_owned(),
             msg: msg.map(|s| s.to_owned()),
+            fields: vec![],
+            request_id: None,
+            retry_after: None,
             http_status_code,
         }
     }
+
+    /// A 422 listing every invalid field of the request
+    pub fn invalid_fields(fields: Vec<FieldError>) -> Self {
+        let names = fields
+            .iter()
+            .map(|f| f.field.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+        Self {
+            fields,
+            ..Self::new(
+                "ValidationFailed",
+                Some(&format!("invalid fields: {}", names)),
+                422,
+            )
+        }
+    }
 }
 
 impl OpenApiResponderInner for Error {
-    fn responses(_generator: &mut OpenApiGenerator) -> Result<Responses, OpenApiError> {
-        use rocket_okapi::okapi::openapi3::{RefOr, Response as OpenApiReponse};
+    fn responses(generator: &mut OpenApiGenerator) -> Result<Responses, OpenApiError> {
+        use rocket_okapi::okapi::openapi3::{MediaType, RefOr, Response as OpenApiReponse};
+
+        let content = Map::from([(
+            "application/json".to_string(),
+            MediaType {
+                schema: Some(generator.json_schema::<Error>()),
+                ..Default::default()
+            },
+        )]);
 
         let mut responses = Map::new();
         responses.insert(
@@ -57,12 +105,35 @@ impl OpenApiResponderInner for Error {
                 ..Default::default()
             }),
         );
+        responses.insert(
+            "409".to_string(),
+            RefOr::Object(OpenApiReponse {
+                description: "\
+                # [409 Conflict](https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/409)\n\
+                This response is given when a resource with the same name already exists. \
+                "
+                .to_string(),
+                ..Default::default()
+            }),
+        );
         responses.insert(
             "422".to_string(),
             RefOr::Object(OpenApiReponse {
                 description: "\
                 # [422 Unprocessable Entity](https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/422)\n\
                 This response is given when you request body is not correctly formatted. \
+                Requests that fail validation list every invalid field in `fields`. \
+                ".to_string(),
+                content,
+                ..Default::default()
+            }),
+        );
+        responses.insert(
+            "429".to_string(),
+            RefOr::Object(OpenApiReponse {
+                description: "\
+                # [429 Too Many Requests](https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/429)\n\
+                This response is given when an AWS account or service quota would be exceeded. \
                 ".to_string(),
                 ..Default::default()
             }),
@@ -77,6 +148,27 @@ impl OpenApiResponderInner for Error {
                 ..Default::default()
             }),
         );
+        responses.insert(
+            "502".to_string(),
+            RefOr::Object(OpenApiReponse {
+                description: "\
+                # [502 Bad Gateway](https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/502)\n\
+                This response is given when AWS rejected the service's credentials or permissions. \
+                "
+                .to_string(),
+                ..Default::default()
+            }),
+        );
+        responses.insert(
+            "503".to_string(),
+            RefOr::Object(OpenApiReponse {
+                description: "\
+                # [503 Service Unavailable](https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/503)\n\
+                This response is given when AWS throttled the request or is unavailable; retry after `Retry-After` seconds. \
+                ".to_string(),
+                ..Default::default()
+            }),
+        );
         Ok(Responses {
             responses,
             ..Default::default()
@@ -101,11 +193,15 @@ impl<'r> Responder<'r, 'static> for Error {
     fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
         // Convert object to json
         let body = serde_json::to_string(&self).unwrap();
-        Response::build()
+        let mut response = Response::build();
+        response
             .sized_body(body.len(), std::io::Cursor::new(body))
             .header(ContentType::JSON)
-            .status(Status::new(self.http_status_code))
-            .ok()
+            .status(Status::new(self.http_status_code));
+        if let Some(seconds) = self.retry_after {
+            response.raw_header("Retry-After", seconds.to_string());
+        }
+        response.ok()
     }
 }
 
@@ -116,15 +212,211 @@ impl From<rocket::serde::json::Error<'_>> for Error {
             Io(io_error) => Error {
                 err: "IO Error".to_owned(),
                 msg: Some(io_error.to_string()),
+                fields: vec![],
+                request_id: None,
+                retry_after: None,
                 http_status_code: 422,
             },
             Parse(_raw_data, parse_error) => Error {
                 err: "Parse Error".to_owned(),
                 msg: Some(parse_error.to_string()),
+                fields: vec![],
+                request_id: None,
+                retry_after: None,
                 http_status_code: 422,
             },
         }
     }
 }
 
+/// How a call to the cloud failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloudErrorKind {
+    /// The request was rate limited
+    Throttled,
+    /// The service could not be reached, timed out or failed internally
+    Unavailable,
+    /// A resource with the same name already exists
+    AlreadyExists,
+    /// The resource does not exist (any more)
+    NotFound,
+    /// The service rejected the request's parameters
+    InvalidRequest,
+    /// An account or service quota would be exceeded
+    LimitExceeded,
+    /// The service's credentials were rejected or lack a permission
+    AccessDenied,
+    Other,
+}
+
+impl CloudErrorKind {
+    /// Classify an AWS error code such as `Throttling` or
+    /// `InvalidGroup.Duplicate`
+    pub fn from_code(code: &str) -> Self {
+        const THROTTLED: [&str; 8] = [
+            "Throttling",
+            "ThrottlingException",
+            "ThrottledException",
+            "RequestLimitExceeded",
+            "RequestThrottled",
+            "TooManyRequestsException",
+            "PriorRequestNotComplete",
+            "SlowDown",
+        ];
+        const ACCESS_DENIED: [&str; 10] = [
+            "AccessDenied",
+            "AccessDeniedException",
+            "UnauthorizedOperation",
+            "AuthFailure",
+            "InvalidClientTokenId",
+            "ExpiredToken",
+            "ExpiredTokenException",
+            "SignatureDoesNotMatch",
+            "UnrecognizedClientException",
+            "MissingAuthenticationToken",
+        ];
+        const UNAVAILABLE: [&str; 5] = [
+            "ServiceUnavailable",
+            "ServiceUnavailableException",
+            "InternalError",
+            "InternalFailure",
+            "InternalServiceError",
+        ];
+        if THROTTLED.contains(&code) {
+            CloudErrorKind::Throttled
+        } else if ACCESS_DENIED.contains(&code) {
+            CloudErrorKind::AccessDenied
+        } else if UNAVAILABLE.contains(&code) {
+            CloudErrorKind::Unavailable
+        } else if code.contains("AlreadyExist")
+            || code.contains("Duplicate")
+            || code == "ResourceExistsException"
+        {
+            CloudErrorKind::AlreadyExists
+        } else if code.contains("NotFound") || code.starts_with("NoSuch") {
+            CloudErrorKind::NotFound
+        } else if code.contains("LimitExceeded")
+            || code.starts_with("TooMany")
+            || code.contains("QuotaExceeded")
+        {
+            CloudErrorKind::LimitExceeded
+        } else if code.starts_with("Invalid")
+            || code.starts_with("Validation")
+            || code.starts_with("Missing")
+            || code.contains("Malformed")
+        {
+            CloudErrorKind::InvalidRequest
+        } else {
+            CloudErrorKind::Other
+        }
+    }
+
+    /// The stable `err` code clients see
+    pub fn code(self) -> &'static str {
+        match self {
+            CloudErrorKind::Throttled => "CloudThrottled",
+            CloudErrorKind::Unavailable => "CloudUnavailable",
+            CloudErrorKind::AlreadyExists => "CloudResourceAlreadyExists",
+            CloudErrorKind::NotFound => "CloudResourceNotFound",
+            CloudErrorKind::InvalidRequest => "CloudRequestInvalid",
+            CloudErrorKind::LimitExceeded => "CloudLimitExceeded",
+            CloudErrorKind::AccessDenied => "CloudAccessDenied",
+            CloudErrorKind::Other => "CloudRequestFailed",
+        }
+    }
+
+    pub fn http_status_code(self) -> u16 {
+        match self {
+            CloudErrorKind::Throttled | CloudErrorKind::Unavailable => 503,
+            CloudErrorKind::AlreadyExists => 409,
+            CloudErrorKind::NotFound => 404,
+            CloudErrorKind::InvalidRequest => 400,
+            CloudErrorKind::LimitExceeded => 429,
+            CloudErrorKind::AccessDenied => 502,
+            CloudErrorKind::Other => 500,
+        }
+    }
+
+    /// Seconds to wait before retrying, for failures that are worth retrying
+    pub fn retry_after(self) -> Option<u64> {
+        match self {
+            CloudErrorKind::Throttled => Some(5),
+            CloudErrorKind::Unavailable => Some(30),
+            _ => None,
+        }
+    }
+}
+
+/// A failed call to the cloud
+#[derive(Debug, Clone)]
+pub struct CloudError {
+    pub kind: CloudErrorKind,
+    /// The provider's error code, e.g. `InvalidGroup.Duplicate`
+    pub code: Option<String>,
+    pub message: String,
+    pub request_id: Option<String>,
+}
+
+impl CloudError {
+    pub fn new(kind: CloudErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            code: None,
+            message: message.into(),
+            request_id: None,
+        }
+    }
+
+    /// An error the provider reported with `code`
+    pub fn from_code(code: &str, message: impl Into<String>, request_id: Option<&str>) -> Self {
+        Self {
+            kind: CloudErrorKind::from_code(code),
+            code: Some(code.to_string()),
+            message: message.into(),
+            request_id: request_id.map(|id| id.to_string()),
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.kind == CloudErrorKind::NotFound
+    }
+
+    /// Describe the error as the failure of `step`, e.g.
+    /// `ListenerCreationFailed`, keeping its classification
+    pub fn during(self, step: &str) -> Error {
+        let mut error = Error::from(self);
+        error.msg = error.msg.map(|msg| format!("{}: {}", step, msg));
+        error
+    }
+}
+
+impl std::fmt::Display for CloudError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(code) = &self.code {
+            write!(formatter, "{}: ", code)?;
+        }
+        write!(formatter, "{}", self.message)?;
+        if let Some(request_id) = &self.request_id {
+            write!(formatter, " (request id {})", request_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CloudError {}
+
+impl From<CloudError> for Error {
+    fn from(e: CloudError) -> Self {
+        Self {
+            request_id: e.request_id.clone(),
+            retry_after: e.kind.retry_after(),
+            ..Self::new(
+                e.kind.code(),
+                Some(&e.to_string()),
+                e.kind.http_status_code(),
+            )
+        }
+    }
+}
+
 pub type OResult<T> = std::result::Result<rocket::serde::json::Json<T>, Error>;
@@ -0,0 +1,844 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use crate::cloud::{
+    Certificate, CloudProvider, Instance, LoadBalancer, LoadBalancerState, TargetHealth,
+    ValidationRecord,
+};
+use crate::deployment::RecordSet;
+use crate::error::{CloudError, CloudErrorKind};
+use crate::plan::{
+    AutoScalingGroupPlan, CertificatePlan, IngressRulePlan, IngressSource, LaunchTemplatePlan,
+    ListenerPlan, ListenerRulePlan, LoadBalancerPlan, SecurityGroupPlan, TargetGroupPlan,
+};
+use crate::secrets::{LocalSecretStore, SecretStore};
+
+/// Every resource that currently exists in a [`FakeCloud`], by id or ARN
+#[derive(Clone, Debug, Default)]
+pub struct FakeResources {
+    /// id -> name
+    pub launch_templates: BTreeMap<String, String>,
+    /// name -> attached target group ARNs
+    pub auto_scaling_groups: BTreeMap<String, Vec<String>>,
+    /// ARN -> name
+    pub target_groups: BTreeMap<String, String>,
+    /// ARNs of the target groups with health checks disabled
+    unchecked_target_groups: BTreeSet<String>,
+    /// id -> ingress rules, with the groups they admit referred to by id
+    pub security_groups: BTreeMap<String, Vec<IngressRulePlan>>,
+    security_group_names: BTreeMap<String, String>,
+    /// launch template name -> the security group of its instances
+    pub launch_template_security_groups: BTreeMap<String, String>,
+    /// launch template name -> its user data
+    pub launch_template_user_data: BTreeMap<String, String>,
+    /// load balancer ARN -> its security group
+    load_balancer_security_groups: BTreeMap<String, String>,
+    /// auto scaling group name -> instances it keeps in service
+    auto_scaling_group_sizes: BTreeMap<String, i64>,
+    /// auto scaling group name -> the name of its launch template
+    auto_scaling_group_launch_templates: BTreeMap<String, String>,
+    /// ARN -> name
+    pub load_balancers: BTreeMap<String, String>,
+    /// ARN -> listener
+    pub listeners: BTreeMap<String, FakeListener>,
+    /// ARN -> listener rule
+    pub listener_rules: BTreeMap<String, FakeListenerRule>,
+    /// ARN -> certificate
+    pub certificates: BTreeMap<String, FakeCertificate>,
+    /// (name, type) -> value, or the DNS name an alias points at
+    pub record_sets: BTreeMap<(String, String), String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FakeListener {
+    pub protocol: String,
+    pub port: i64,
+    /// Set when the listener forwards rather than redirects
+    pub target_group_arn: Option<String>,
+    pub certificate_arn: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FakeListenerRule {
+    pub listener_arn: String,
+    pub priority: i64,
+    pub host_header: Option<String>,
+    pub path_pattern: Option<String>,
+    pub target_group_arn: String,
+}
+
+/// A certificate, issued as soon as its validation record exists
+#[derive(Clone, Debug)]
+pub struct FakeCertificate {
+    pub domain_name: String,
+    pub validation_record: ValidationRecord,
+}
+
+impl FakeResources {
+    pub fn is_empty(&self) -> bool {
+        self.launch_templates.is_empty()
+            && self.auto_scaling_groups.is_empty()
+            && self.target_groups.is_empty()
+            && self.security_groups.is_empty()
+            && self.load_balancers.is_empty()
+            && self.listeners.is_empty()
+            && self.listener_rules.is_empty()
+            && self.certificates.is_empty()
+            && self.record_sets.is_empty()
+    }
+}
+
+/// The domain of every other hosted zone
+pub const ZONE_NAME: &str = "example.test";
+/// The domain of every hosted zone whose id contains `PRIVATE`
+pub const PRIVATE_ZONE_NAME: &str = "internal.example.test";
+
+fn zone_name(hosted_zone_id: &str) -> &'static str {
+    if hosted_zone_id.contains("PRIVATE") {
+        PRIVATE_ZONE_NAME
+    } else {
+        ZONE_NAME
+    }
+}
+
+/// An in-memory cloud for tests
+///
+/// Resources are created instantly and names must be unique per resource
+/// type, as they are in AWS. Subnets are public unless their id contains
+/// `private`, and hosted zones are too unless theirs contains `PRIVATE`.
+/// [`FakeCloud::fail`] and [`FakeCloud::fail_with`]
+/// make an operation fail so rollback and error handling can be exercised.
+#[derive(Default)]
+pub struct FakeCloud {
+    resources: Mutex<FakeResources>,
+    /// operation -> the error it fails with, and how many more times if limited
+    failing: Mutex<HashMap<String, (CloudErrorKind, Option<u32>)>>,
+    next_id: Mutex<u64>,
+    /// The state every load balancer reports instead of `active`
+    load_balancer_state: Mutex<Option<LoadBalancerState>>,
+    /// The state and reason every target reports instead of `healthy`
+    target_health: Mutex<Option<(String, Option<String>)>>,
+    secrets: LocalSecretStore,
+}
+
+impl FakeCloud {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make every later call to `operation`, a [`CloudProvider`] method name
+    /// such as `create_listener`, fail
+    pub fn fail(&self, operation: &str) {
+        self.fail_with(operation, CloudErrorKind::Other);
+    }
+
+    /// Make every later call to `operation` fail with an error of `kind`
+    pub fn fail_with(&self, operation: &str, kind: CloudErrorKind) {
+        self.failing
+            .lock()
+            .unwrap()
+            .insert(operation.to_string(), (kind, None));
+    }
+
+    /// Make only the next `times` calls to `operation` fail with an error of
+    /// `kind`, like a transient failure
+    pub fn fail_times(&self, operation: &str, kind: CloudErrorKind, times: u32) {
+        self.failing
+            .lock()
+            .unwrap()
+            .insert(operation.to_string(), (kind, Some(times)));
+    }
+
+    /// Make every load balancer report `code` instead of being active
+    pub fn set_load_balancer_state(&self, code: &str, reason: Option<&str>) {
+        *self.load_balancer_state.lock().unwrap() = Some(LoadBalancerState {
+            code: code.to_string(),
+            reason: reason.map(|r| r.to_string()),
+        });
+    }
+
+    /// Make every target report `state` instead of being healthy
+    pub fn set_target_health(&self, state: &str, reason: Option<&str>) {
+        *self.target_health.lock().unwrap() =
+            Some((state.to_string(), reason.map(|r| r.to_string())));
+    }
+
+    pub fn resources(&self) -> FakeResources {
+        self.resources.lock().unwrap().clone()
+    }
+
+    fn check(&self, operation: &str) -> Result<(), CloudError> {
+        let mut failing = self.failing.lock().unwrap();
+        let Some((kind, remaining)) = failing.get_mut(operation) else {
+            return Ok(());
+        };
+        let kind = *kind;
+        match remaining {
+            Some(0) => return Ok(()),
+            Some(n) => *n -= 1,
+            None => {}
+        }
+        drop(failing);
+        Err(CloudError {
+            request_id: Some(self.id("request")),
+            ..CloudError::new(kind, format!("{} failed: injected failure", operation))
+        })
+    }
+
+    fn id(&self, prefix: &str) -> String {
+        let mut next_id = self.next_id.lock().unwrap();
+        *next_id += 1;
+        format!("{}-{:08x}", prefix, *next_id)
+    }
+}
+
+/// Whether the validation record of `certificate` is in DNS
+fn issued(resources: &FakeResources, certificate: &FakeCertificate) -> bool {
+    let record = &certificate.validation_record;
+    let key = (record.name.clone(), record.record_type.clone());
+    resources.record_sets.get(&key) == Some(&record.value)
+}
+
+fn not_found(resource: &str, id: &str) -> CloudError {
+    CloudError::new(
+        CloudErrorKind::NotFound,
+        format!("{} {} not found", resource, id),
+    )
+}
+
+fn duplicate(resource: &str, name: &str) -> CloudError {
+    CloudError::new(
+        CloudErrorKind::AlreadyExists,
+        format!("{} {} already exists", resource, name),
+    )
+}
+
+#[async_trait]
+impl CloudProvider for FakeCloud {
+    fn secrets(&self) -> &dyn SecretStore {
+        &self.secrets
+    }
+
+    async fn create_launch_template(
+        &self,
+        plan: &LaunchTemplatePlan,
+        security_group_id: &str,
+        user_data: Option<&str>,
+    ) -> Result<String, CloudError> {
+        self.check("create_launch_template")?;
+        let id = self.id("lt");
+        let mut resources = self.resources.lock().unwrap();
+        if resources.launch_templates.values().any(|n| n == &plan.name) {
+            return Err(duplicate("launch template", &plan.name));
+        }
+        if !resources.security_groups.contains_key(security_group_id) {
+            return Err(not_found("security group", security_group_id));
+        }
+        resources
+            .launch_templates
+            .insert(id.clone(), plan.name.clone());
+        resources
+            .launch_template_security_groups
+            .insert(plan.name.clone(), security_group_id.to_string());
+        if let Some(user_data) = user_data {
+            resources
+                .launch_template_user_data
+                .insert(plan.name.clone(), user_data.to_string());
+        }
+        Ok(id)
+    }
+
+    async fn delete_launch_template(&self, id: &str) -> Result<(), CloudError> {
+        self.check("delete_launch_template")?;
+        let mut resources = self.resources.lock().unwrap();
+        let name = resources
+            .launch_templates
+            .remove(id)
+            .ok_or_else(|| not_found("launch template", id))?;
+        resources.launch_template_security_groups.remove(&name);
+        resources.launch_template_user_data.remove(&name);
+        Ok(())
+    }
+
+    async fn create_auto_scaling_group(
+        &self,
+        plan: &AutoScalingGroupPlan,
+    ) -> Result<(), CloudError> {
+        self.check("create_auto_scaling_group")?;
+        let mut resources = self.resources.lock().unwrap();
+        if resources.auto_scaling_groups.contains_key(&plan.name) {
+            return Err(duplicate("auto scaling group", &plan.name));
+        }
+        if !resources
+            .launch_templates
+            .values()
+            .any(|n| n == &plan.launch_template_name)
+        {
+            return Err(not_found("launch template", &plan.launch_template_name));
+        }
+        resources
+            .auto_scaling_groups
+            .insert(plan.name.clone(), vec![]);
+        resources
+            .auto_scaling_group_sizes
+            .insert(plan.name.clone(), plan.min_size);
+        resources
+            .auto_scaling_group_launch_templates
+            .insert(plan.name.clone(), plan.launch_template_name.clone());
+        Ok(())
+    }
+
+    async fn delete_auto_scaling_group(&self, name: &str) -> Result<(), CloudError> {
+        self.check("delete_auto_scaling_group")?;
+        let mut resources = self.resources.lock().unwrap();
+        resources.auto_scaling_group_sizes.remove(name);
+        resources.auto_scaling_group_launch_templates.remove(name);
+        resources
+            .auto_scaling_groups
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| not_found("auto scaling group", name))
+    }
+
+    async fn auto_scaling_group_exists(&self, name: &str) -> Result<bool, CloudError> {
+        self.check("auto_scaling_group_exists")?;
+        let resources = self.resources.lock().unwrap();
+        Ok(resources.auto_scaling_groups.contains_key(name))
+    }
+
+    async fn auto_scaling_group_instances(&self, name: &str) -> Result<Vec<Instance>, CloudError> {
+        self.check("auto_scaling_group_instances")?;
+        let resources = self.resources.lock().unwrap();
+        let size = resources.auto_scaling_group_sizes.get(name).copied();
+        Ok((0..size.unwrap_or_default())
+            .map(|i| Instance {
+                id: format!("i-{}-{}", name, i),
+                lifecycle_state: "InService".to_string(),
+                health_status: "Healthy".to_string(),
+            })
+            .collect())
+    }
+
+    async fn attach_target_groups(
+        &self,
+        auto_scaling_group: &str,
+        target_group_arns: &[String],
+    ) -> Result<(), CloudError> {
+        self.check("attach_target_groups")?;
+        let mut resources = self.resources.lock().unwrap();
+        if let Some(arn) = target_group_arns
+            .iter()
+            .find(|arn| !resources.target_groups.contains_key(*arn))
+        {
+            return Err(not_found("target group", arn));
+        }
+        let attached = resources
+            .auto_scaling_groups
+            .get_mut(auto_scaling_group)
+            .ok_or_else(|| not_found("auto scaling group", auto_scaling_group))?;
+        attached.extend(target_group_arns.iter().cloned());
+        Ok(())
+    }
+
+    async fn detach_target_groups(
+        &self,
+        auto_scaling_group: &str,
+        target_group_arns: &[String],
+    ) -> Result<(), CloudError> {
+        self.check("detach_target_groups")?;
+        let mut resources = self.resources.lock().unwrap();
+        let attached = resources
+            .auto_scaling_groups
+            .get_mut(auto_scaling_group)
+            .ok_or_else(|| not_found("auto scaling group", auto_scaling_group))?;
+        attached.retain(|arn| !target_group_arns.contains(arn));
+        Ok(())
+    }
+
+    async fn create_target_group(&self, plan: &TargetGroupPlan) -> Result<String, CloudError> {
+        self.check("create_target_group")?;
+        let arn = self.id("arn:fake:targetgroup");
+        let mut resources = self.resources.lock().unwrap();
+        if resources.target_groups.values().any(|n| n == &plan.name) {
+            return Err(duplicate("target group", &plan.name));
+        }
+        resources
+            .target_groups
+            .insert(arn.clone(), plan.name.clone());
+        if plan.health_check_enabled == Some(false) {
+            resources.unchecked_target_groups.insert(arn.clone());
+        }
+        Ok(arn)
+    }
+
+    async fn delete_target_group(&self, arn: &str) -> Result<(), CloudError> {
+        self.check("delete_target_group")?;
+        let mut resources = self.resources.lock().unwrap();
+        if resources
+            .listeners
+            .values()
+            .any(|l| l.target_group_arn.as_deref() == Some(arn))
+            || resources
+                .listener_rules
+                .values()
+                .any(|r| r.target_group_arn == arn)
+        {
+            return Err(CloudError::from_code(
+                "ResourceInUse",
+                format!("target group {} is in use by a listener", arn),
+                None,
+            ));
+        }
+        resources.unchecked_target_groups.remove(arn);
+        resources
+            .target_groups
+            .remove(arn)
+            .map(|_| ())
+            .ok_or_else(|| not_found("target group", arn))
+    }
+
+    async fn target_health(&self, arn: &str) -> Result<Vec<TargetHealth>, CloudError> {
+        self.check("target_health")?;
+        let resources = self.resources.lock().unwrap();
+        if !resources.target_groups.contains_key(arn) {
+            return Err(not_found("target group", arn));
+        }
+        // as ELB reports the targets of groups without health checks
+        let (state, reason) = if resources.unchecked_target_groups.contains(arn) {
+            (
+                "unavailable".to_string(),
+                Some("Target.HealthCheckDisabled".to_string()),
+            )
+        } else {
+            self.target_health
+                .lock()
+                .unwrap()
+                .clone()
+                .unwrap_or_else(|| ("healthy".to_string(), None))
+        };
+        // every instance of a group the target group is attached to
+        Ok(resources
+            .auto_scaling_groups
+            .iter()
+            .filter(|(_, attached)| attached.iter().any(|a| a == arn))
+            .flat_map(|(name, _)| {
+                let size = resources.auto_scaling_group_sizes[name];
+                (0..size).map(move |i| format!("i-{}-{}", name, i))
+            })
+            .map(|id| TargetHealth {
+                id,
+                port: None,
+                state: state.clone(),
+                reason: reason.clone(),
+                description: None,
+            })
+            .collect())
+    }
+
+    async fn create_security_group(&self, plan: &SecurityGroupPlan) -> Result<String, CloudError> {
+        self.check("create_security_group")?;
+        let id = self.id("sg");
+        let mut resources = self.resources.lock().unwrap();
+        if resources
+            .security_group_names
+            .values()
+            .any(|n| n == &plan.name)
+        {
+            return Err(duplicate("security group", &plan.name));
+        }
+        resources.security_groups.insert(id.clone(), vec![]);
+        resources
+            .security_group_names
+            .insert(id.clone(), plan.name.clone());
+        Ok(id)
+    }
+
+    async fn authorize_ingress(
+        &self,
+        group_id: &str,
+        rule: &IngressRulePlan,
+        source_group_id: Option<&str>,
+    ) -> Result<(), CloudError> {
+        self.check("authorize_ingress")?;
+        let mut resources = self.resources.lock().unwrap();
+        let mut rule = rule.clone();
+        if let IngressSource::SecurityGroup(name) | IngressSource::SecurityGroupId(name) =
+            &rule.source
+        {
+            let source = source_group_id
+                .filter(|id| resources.security_groups.contains_key(*id))
+                .ok_or_else(|| not_found("security group", name))?;
+            rule.source = IngressSource::SecurityGroup(source.to_string());
+        }
+        let rules = resources
+            .security_groups
+            .get_mut(group_id)
+            .ok_or_else(|| not_found("security group", group_id))?;
+        rules.push(rule);
+        Ok(())
+    }
+
+    async fn delete_security_group(&self, group_id: &str) -> Result<(), CloudError> {
+        self.check("delete_security_group")?;
+        let mut resources = self.resources.lock().unwrap();
+        let referenced = resources
+            .security_groups
+            .values()
+            .flatten()
+            .any(|rule| rule.source == IngressSource::SecurityGroup(group_id.to_string()));
+        let used_by_instances = resources
+            .auto_scaling_groups
+            .keys()
+            .filter_map(|name| resources.auto_scaling_group_launch_templates.get(name))
+            .any(|template| {
+                resources.launch_template_security_groups.get(template)
+                    == Some(&group_id.to_string())
+            });
+        let used_by_load_balancer = resources
+            .load_balancer_security_groups
+            .values()
+            .any(|g| g == group_id);
+        if referenced || used_by_instances || used_by_load_balancer {
+            return Err(CloudError::from_code(
+                "DependencyViolation",
+                format!("security group {} has a dependent object", group_id),
+                None,
+            ));
+        }
+        resources.security_group_names.remove(group_id);
+        resources
+            .security_groups
+            .remove(group_id)
+            .map(|_| ())
+            .ok_or_else(|| not_found("security group", group_id))
+    }
+
+    async fn subnet_is_public(&self, subnet_id: &str) -> Result<bool, CloudError> {
+        self.check("subnet_is_public")?;
+        Ok(!subnet_id.contains("private"))
+    }
+
+    async fn create_load_balancer(
+        &self,
+        plan: &LoadBalancerPlan,
+        security_group_id: &str,
+    ) -> Result<LoadBalancer, CloudError> {
+        self.check("create_load_balancer")?;
+        let arn = self.id("arn:fake:loadbalancer");
+        let mut resources = self.resources.lock().unwrap();
+        if resources.load_balancers.values().any(|n| n == &plan.name) {
+            return Err(duplicate("load balancer", &plan.name));
+        }
+        if !resources.security_groups.contains_key(security_group_id) {
+            return Err(not_found("security group", security_group_id));
+        }
+        resources
+            .load_balancers
+            .insert(arn.clone(), plan.name.clone());
+        resources
+            .load_balancer_security_groups
+            .insert(arn.clone(), security_group_id.to_string());
+        Ok(LoadBalancer {
+            arn,
+            dns_name: format!("{}.elb.fake", plan.name),
+            canonical_hosted_zone_id: "ZFAKELOADBALANCER".to_string(),
+        })
+    }
+
+    async fn load_balancer_state(
+        &self,
+        arn: &str,
+    ) -> Result<Option<LoadBalancerState>, CloudError> {
+        self.check("load_balancer_state")?;
+        let state = self.load_balancer_state.lock().unwrap().clone();
+        let resources = self.resources.lock().unwrap();
+        Ok(resources.load_balancers.get(arn).map(|_| {
+            state.unwrap_or_else(|| LoadBalancerState {
+                code: "active".to_string(),
+                reason: None,
+            })
+        }))
+    }
+
+    async fn delete_load_balancer(&self, arn: &str) -> Result<(), CloudError> {
+        self.check("delete_load_balancer")?;
+        let mut resources = self.resources.lock().unwrap();
+        resources.load_balancer_security_groups.remove(arn);
+        resources
+            .load_balancers
+            .remove(arn)
+            .map(|_| ())
+            .ok_or_else(|| not_found("load balancer", arn))
+    }
+
+    async fn load_balancer(&self, arn: &str) -> Result<Option<LoadBalancer>, CloudError> {
+        self.check("load_balancer")?;
+        let resources = self.resources.lock().unwrap();
+        Ok(resources.load_balancers.get(arn).map(|name| LoadBalancer {
+            arn: arn.to_string(),
+            dns_name: format!("{}.elb.fake", name),
+            canonical_hosted_zone_id: "ZFAKELOADBALANCER".to_string(),
+        }))
+    }
+
+    async fn create_listener(
+        &self,
+        load_balancer_arn: &str,
+        plan: &ListenerPlan,
+        target_group_arn: Option<&str>,
+        certificate_arn: Option<&str>,
+    ) -> Result<String, CloudError> {
+        self.check("create_listener")?;
+        let arn = self.id("arn:fake:listener");
+        let mut resources = self.resources.lock().unwrap();
+        if !resources.load_balancers.contains_key(load_balancer_arn) {
+            return Err(not_found("load balancer", load_balancer_arn));
+        }
+        if let Some(target_group_arn) = target_group_arn {
+            if !resources.target_groups.contains_key(target_group_arn) {
+                return Err(not_found("target group", target_group_arn));
+            }
+        }
+        if let Some(certificate_arn) = certificate_arn {
+            let certificate = resources
+                .certificates
+                .get(certificate_arn)
+                .ok_or_else(|| not_found("certificate", certificate_arn))?;
+            if !issued(&resources, certificate) {
+                return Err(CloudError::from_code(
+                    "UnsupportedCertificate",
+                    format!("certificate {} is not issued", certificate_arn),
+                    None,
+                ));
+            }
+        }
+        resources.listeners.insert(
+            arn.clone(),
+            FakeListener {
+                protocol: plan.protocol.clone(),
+                port: plan.port,
+                target_group_arn: target_group_arn.map(|a| a.to_string()),
+                certificate_arn: certificate_arn.map(|a| a.to_string()),
+            },
+        );
+        Ok(arn)
+    }
+
+    async fn delete_listener(&self, arn: &str) -> Result<(), CloudError> {
+        self.check("delete_listener")?;
+        let mut resources = self.resources.lock().unwrap();
+        resources
+            .listener_rules
+            .retain(|_, r| r.listener_arn != arn);
+        resources
+            .listeners
+            .remove(arn)
+            .map(|_| ())
+            .ok_or_else(|| not_found("listener", arn))
+    }
+
+    async fn listener_rule_priorities(&self, listener_arn: &str) -> Result<Vec<i64>, CloudError> {
+        self.check("listener_rule_priorities")?;
+        let resources = self.resources.lock().unwrap();
+        if !resources.listeners.contains_key(listener_arn) {
+            return Err(not_found("listener", listener_arn));
+        }
+        Ok(resources
+            .listener_rules
+            .values()
+            .filter(|r| r.listener_arn == listener_arn)
+            .map(|r| r.priority)
+            .collect())
+    }
+
+    async fn create_listener_rule(
+        &self,
+        listener_arn: &str,
+        plan: &ListenerRulePlan,
+        priority: i64,
+        target_group_arn: &str,
+    ) -> Result<String, CloudError> {
+        self.check("create_listener_rule")?;
+        let arn = self.id("arn:fake:listener-rule");
+        let mut resources = self.resources.lock().unwrap();
+        if !resources.listeners.contains_key(listener_arn) {
+            return Err(not_found("listener", listener_arn));
+        }
+        if !resources.target_groups.contains_key(target_group_arn) {
+            return Err(not_found("target group", target_group_arn));
+        }
+        if resources
+            .listener_rules
+            .values()
+            .any(|r| r.listener_arn == listener_arn && r.priority == priority)
+        {
+            return Err(CloudError::from_code(
+                "PriorityInUse",
+                format!("priority {} is in use", priority),
+                None,
+            ));
+        }
+        resources.listener_rules.insert(
+            arn.clone(),
+            FakeListenerRule {
+                listener_arn: listener_arn.to_string(),
+                priority,
+                host_header: plan.host_header.clone(),
+                path_pattern: plan.path_pattern.clone(),
+                target_group_arn: target_group_arn.to_string(),
+            },
+        );
+        Ok(arn)
+    }
+
+    async fn delete_listener_rule(&self, arn: &str) -> Result<(), CloudError> {
+        self.check("delete_listener_rule")?;
+        let mut resources = self.resources.lock().unwrap();
+        resources
+            .listener_rules
+            .remove(arn)
+            .map(|_| ())
+            .ok_or_else(|| not_found("listener rule", arn))
+    }
+
+    async fn request_certificate(&self, plan: &CertificatePlan) -> Result<String, CloudError> {
+        self.check("request_certificate")?;
+        let arn = self.id("arn:fake:certificate");
+        // like ACM, every certificate for a domain validates with the same record
+        let token = plan.domain_name.replace('.', "-");
+        let mut resources = self.resources.lock().unwrap();
+        resources.certificates.insert(
+            arn.clone(),
+            FakeCertificate {
+                domain_name: plan.domain_name.clone(),
+                validation_record: ValidationRecord {
+                    name: format!("_{}.{}.", token, plan.domain_name),
+                    record_type: "CNAME".to_string(),
+                    value: format!("_{}.acm-validations.fake.", token),
+                },
+            },
+        );
+        Ok(arn)
+    }
+
+    async fn certificate(&self, arn: &str) -> Result<Certificate, CloudError> {
+        self.check("certificate")?;
+        let resources = self.resources.lock().unwrap();
+        let certificate = resources
+            .certificates
+            .get(arn)
+            .ok_or_else(|| not_found("certificate", arn))?;
+        Ok(Certificate {
+            status: if issued(&resources, certificate) {
+                "ISSUED"
+            } else {
+                "PENDING_VALIDATION"
+            }
+            .to_string(),
+            validation_records: vec![certificate.validation_record.clone()],
+        })
+    }
+
+    async fn delete_certificate(&self, arn: &str) -> Result<(), CloudError> {
+        self.check("delete_certificate")?;
+        let mut resources = self.resources.lock().unwrap();
+        if resources
+            .listeners
+            .values()
+            .any(|l| l.certificate_arn.as_deref() == Some(arn))
+        {
+            return Err(CloudError::from_code(
+                "ResourceInUseException",
+                format!("certificate {} is in use by a listener", arn),
+                None,
+            ));
+        }
+        resources
+            .certificates
+            .remove(arn)
+            .map(|_| ())
+            .ok_or_else(|| not_found("certificate", arn))
+    }
+
+    async fn hosted_zone_name(&self, hosted_zone_id: &str) -> Result<String, CloudError> {
+        self.check("hosted_zone_name")?;
+        Ok(zone_name(hosted_zone_id).to_string())
+    }
+
+    async fn record_set_change_status(&self, _change_id: &str) -> Result<String, CloudError> {
+        self.check("record_set_change_status")?;
+        Ok("INSYNC".to_string())
+    }
+
+    async fn change_record_set(
+        &self,
+        action: &str,
+        record_set: &RecordSet,
+    ) -> Result<String, CloudError> {
+        self.check("change_record_set")?;
+        let name = record_set.name.trim_end_matches('.');
+        let zone = zone_name(&record_set.hosted_zone_id);
+        if name != zone && !name.ends_with(&format!(".{}", zone)) {
+            return Err(CloudError::from_code(
+                "InvalidChangeBatch",
+                format!("{} is not in the zone {}", name, zone),
+                None,
+            ));
+        }
+        let key = (record_set.name.clone(), record_set.record_type.clone());
+        let value = match (&record_set.value, &record_set.alias_target) {
+            (Some(value), None) => value.clone(),
+            (None, Some(target)) => target.dns_name.clone(),
+            _ => {
+                return Err(CloudError::from_code(
+                    "InvalidInput",
+                    format!("{} needs either a value or an alias target", name),
+                    None,
+                ))
+            }
+        };
+        let change_id = self.id("change");
+        let mut resources = self.resources.lock().unwrap();
+        match action {
+            "CREATE" => {
+                if resources.record_sets.contains_key(&key) {
+                    return Err(duplicate("record set", name));
+                }
+                resources.record_sets.insert(key, value);
+            }
+            "UPSERT" => {
+                resources.record_sets.insert(key, value);
+            }
+            "DELETE" => {
+                let current = resources
+                    .record_sets
+                    .get(&key)
+                    .ok_or_else(|| not_found("record set", name))?;
+                // Route53 only deletes a record whose values all match; one
+                // that has been replaced is no longer ours
+                if *current != value {
+                    let mut error = CloudError::from_code(
+                        "InvalidChangeBatch",
+                        format!(
+                            "Tried to delete resource record set {} but the values provided do not match the current values",
+                            name
+                        ),
+                        None,
+                    );
+                    error.kind = CloudErrorKind::NotFound;
+                    return Err(error);
+                }
+                resources.record_sets.remove(&key);
+            }
+            _ => {
+                return Err(CloudError::new(
+                    CloudErrorKind::InvalidRequest,
+                    format!("unsupported change action {}", action),
+                ))
+            }
+        }
+        Ok(change_id)
+    }
+}
@@ -0,0 +1,181 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::Aes256Gcm;
+use base64::prelude::*;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::okapi::schemars::JsonSchema;
+use sha2::{Digest, Sha256};
+
+use crate::error;
+use crate::File;
+
+/// Largest file that can be delivered to an instance
+pub const MAX_FILE_SIZE: usize = 8 * 1024;
+/// EC2 rejects user data larger than 16 KB before base64 encoding
+pub const MAX_USER_DATA_SIZE: usize = 16 * 1024;
+
+/// AES-GCM nonces are 12 bytes, and add a 16 byte tag to the ciphertext
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+const DEFAULT_MODE: &str = "0600";
+const DEFAULT_OWNER: &str = "root:root";
+
+/// A file written to every instance at boot, as listed in the plan
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct PlannedFile {
+    pub path: String,
+    pub mode: String,
+    pub owner: String,
+    pub size: usize,
+}
+
+/// A file in the user data manifest; `content` is encrypted
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+    path: String,
+    mode: String,
+    owner: String,
+    /// base64 of the 12 byte AES-GCM nonce
+    nonce: String,
+    /// base64 of the AES-256-GCM ciphertext
+    content: String,
+}
+
+/// The user data instances read their files from
+///
+/// Each file is encrypted with AES-256-GCM under the SHA-256 digest of
+/// `FILE_ENCRYPTION_KEY`, which instances read from the deployment's secret
+/// store, so the contents can't be read from the launch template.
+#[derive(Serialize, Deserialize)]
+struct UserData {
+    files: Vec<EncryptedFile>,
+}
+
+fn invalid(err: &str, msg: String) -> error::Error {
+    error::Error::new(err, Some(&msg), 422)
+}
+
+/// Check every file and describe where it will be written, without
+/// encrypting anything
+pub fn plan(files: &[File]) -> Result<Vec<PlannedFile>, error::Error> {
+    let planned = files
+        .iter()
+        .map(|file| {
+            if !file.path.starts_with('/') {
+                return Err(invalid(
+                    "InvalidFilePath",
+                    format!("file path {} must be absolute", file.path),
+                ));
+            }
+            if file.content.len() > MAX_FILE_SIZE {
+                return Err(invalid(
+                    "FileTooLarge",
+                    format!(
+                        "file {} is {} bytes, files may be at most {} bytes",
+                        file.path,
+                        file.content.len(),
+                        MAX_FILE_SIZE
+                    ),
+                ));
+            }
+            let mode = file
+                .mode
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MODE.to_string());
+            if mode.len() < 3 || mode.len() > 4 || !mode.chars().all(|c| ('0'..='7').contains(&c)) {
+                return Err(invalid(
+                    "InvalidFileMode",
+                    format!(
+                        "file {} has mode {}, expected an octal mode such as 0600",
+                        file.path, mode
+                    ),
+                ));
+            }
+            let owner = file
+                .owner
+                .clone()
+                .unwrap_or_else(|| DEFAULT_OWNER.to_string());
+            if owner.is_empty() || owner.contains(char::is_whitespace) {
+                return Err(invalid(
+                    "InvalidFileOwner",
+                    format!("file {} has invalid owner {:?}", file.path, owner),
+                ));
+            }
+            Ok(PlannedFile {
+                path: file.path.clone(),
+                mode,
+                owner,
+                size: file.content.len(),
+            })
+        })
+        .collect::<Result<Vec<PlannedFile>, error::Error>>()?;
+
+    // ciphertext is as long as the content plus its tag, so the size of the
+    // user data is known before encrypting
+    let manifest = planned
+        .iter()
+        .map(|file| EncryptedFile {
+            path: file.path.clone(),
+            mode: file.mode.clone(),
+            owner: file.owner.clone(),
+            nonce: BASE64_STANDARD.encode([0; NONCE_SIZE]),
+            content: BASE64_STANDARD.encode(vec![0; file.size + TAG_SIZE]),
+        })
+        .collect();
+    check_size(files.len(), &serialize(manifest)?)?;
+    Ok(planned)
+}
+
+fn serialize(files: Vec<EncryptedFile>) -> Result<String, error::Error> {
+    serde_json::to_string(&UserData { files })
+        .map_err(|e| error::Error::new("UserDataCreationFailed", Some(&e.to_string()), 500))
+}
+
+fn check_size(count: usize, body: &str) -> Result<(), error::Error> {
+    if body.len() > MAX_USER_DATA_SIZE {
+        return Err(invalid(
+            "UserDataTooLarge",
+            format!(
+                "the {} files add up to {} bytes of user data once encrypted, the limit is {} bytes",
+                count,
+                body.len(),
+                MAX_USER_DATA_SIZE
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Encrypt `files` into base64 encoded user data for the launch template
+///
+/// Every call encrypts under fresh nonces, so this is only done when the
+/// launch template is created, never while planning.
+pub fn user_data(files: &[File]) -> Result<Option<String>, error::Error> {
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let planned = plan(files)?;
+    let key = std::env::var("FILE_ENCRYPTION_KEY").map_err(|e| {
+        error::Error::new("FileEncryptionKeyCreationFailed", Some(&e.to_string()), 500)
+    })?;
+    let cipher = Aes256Gcm::new(&Sha256::digest(key.as_bytes()));
+
+    let mut encrypted = vec![];
+    for (file, planned) in files.iter().zip(planned) {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, file.content.as_bytes())
+            .map_err(|e| error::Error::new("FileEncryptionFailed", Some(&e.to_string()), 500))?;
+        encrypted.push(EncryptedFile {
+            path: planned.path,
+            mode: planned.mode,
+            owner: planned.owner,
+            nonce: BASE64_STANDARD.encode(nonce),
+            content: BASE64_STANDARD.encode(ciphertext),
+        });
+    }
+
+    let body = serialize(encrypted)?;
+    check_size(files.len(), &body)?;
+    Ok(Some(BASE64_STANDARD.encode(body)))
+}
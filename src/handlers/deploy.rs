@@ -0,0 +1,117 @@
+use rocket::response::status::Accepted;
+use rocket::serde::json::Json;
+use rocket::State;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::deployment::{DeploymentPhase, DeploymentRecord};
+use crate::error::{self, OResult};
+use crate::jobs::{Job, JobQueue, Work};
+use crate::plan::Plan;
+use crate::{AppState, DeployAWSInput};
+
+/// Get a deployment
+///
+/// Returns the original input, the current phase and every resource created
+/// for the deployment with the given id.
+#[openapi]
+#[get("/deploy/aws/<id>")]
+pub async fn deploy_aws_status(state: &State<AppState>, id: &str) -> OResult<DeploymentRecord> {
+    let record = state.store.get(id).await.ok_or_else(|| {
+        error::Error::new(
+            "DeploymentNotFound",
+            Some(&format!("no deployment with id {}", id)),
+            404,
+        )
+    })?;
+    Ok(Json(record))
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct DeployAWSDeleteOutput {
+    id: String,
+    phase: DeploymentPhase,
+}
+
+/// Delete a deployment
+///
+/// Queues every resource created for the deployment, looked up by id or by
+/// `deployment_slug`, for a background worker to delete in reverse dependency
+/// order. Returns `202 Accepted` straight away; poll `GET /deploy/aws/<id>`
+/// until the deployment is `Deleted`, with the result for each resource under
+/// `teardown`. Resources that could not be deleted are kept on the deployment,
+/// which is marked `Failed`, so the request can be retried. Deleting a
+/// deployment that is already `Deleted` or `RolledBack` changes nothing and
+/// returns its phase.
+#[openapi]
+#[delete("/deploy/aws/<id>")]
+pub async fn deploy_aws_delete(
+    state: &State<AppState>,
+    jobs: &State<JobQueue>,
+    id: &str,
+) -> Result<Accepted<Json<DeployAWSDeleteOutput>>, error::Error> {
+    let record = match state.store.get(id).await {
+        Some(record) => record,
+        None => state.store.find_by_slug(id).await.ok_or_else(|| {
+            error::Error::new(
+                "DeploymentNotFound",
+                Some(&format!("no deployment with id or slug {}", id)),
+                404,
+            )
+        })?,
+    };
+
+    // nothing is left to delete, and the recorded teardown is kept
+    if matches!(
+        record.phase,
+        DeploymentPhase::Deleted | DeploymentPhase::RolledBack
+    ) {
+        return Ok(Accepted(Json(DeployAWSDeleteOutput {
+            id: record.id,
+            phase: record.phase,
+        })));
+    }
+
+    let lock = state.slug_locks.acquire(&record.input.deployment_slug)?;
+    // fail before the deployment is marked as being deleted if no worker
+    // could delete it
+    let environment = record.plan.environment.clone();
+    state.environment(&environment)?;
+
+    let updated = state
+        .store
+        .update(&record.id, |r| r.phase = DeploymentPhase::Deleting)
+        .await?;
+
+    let job = Job {
+        id: record.id.clone(),
+        work: Work::Teardown { environment },
+        lock,
+    };
+    if let Err(e) = jobs.enqueue(job) {
+        state
+            .store
+            .update(&record.id, |r| r.phase = record.phase)
+            .await?;
+        return Err(e);
+    }
+
+    Ok(Accepted(Json(DeployAWSDeleteOutput {
+        id: updated.id,
+        phase: updated.phase,
+    })))
+}
+
+/// Plan a deployment
+///
+/// Returns every resource `POST /deploy/aws/create` would create for the same
+/// input, in the order they are created, without calling AWS.
+#[openapi]
+#[post("/deploy/aws/plan", data = "<input>")]
+pub async fn deploy_aws_plan(
+    state: &State<AppState>,
+    input: Json<DeployAWSInput>,
+) -> OResult<Plan> {
+    Ok(Json(state.plan(&input)?))
+}
@@ -1 +1,2 @@
-pub mod log;
\ No newline at end of file
+pub mod deploy;
+pub mod log;
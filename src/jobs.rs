@@ -0,0 +1,290 @@
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, Mutex};
+
+use crate::deployment::DeploymentPhase;
+use crate::error;
+use crate::locks::LockGuard;
+use crate::plan::Plan;
+use crate::provision;
+use crate::saga::Saga;
+use crate::teardown;
+use crate::{AppState, File};
+
+/// What a worker does with a deployment
+pub enum Work {
+    /// Provision the planned resources, writing `files` to the instances;
+    /// file contents are never persisted, so they are only held here
+    Provision { plan: Box<Plan>, files: Vec<File> },
+    /// Delete every resource recorded for the deployment in the given
+    /// environment
+    Teardown { environment: String },
+}
+
+/// A deployment waiting to be provisioned or deleted by a worker
+pub struct Job {
+    pub id: String,
+    pub work: Work,
+    /// Keeps the deployment slug locked until the job has finished
+    pub lock: LockGuard,
+}
+
+/// Hands deployments to a pool of background workers
+///
+/// Provisioning and teardown take minutes, so `POST /deploy/aws/create` and
+/// `DELETE /deploy/aws/<id>` only record the deployment and enqueue it here.
+/// Clients poll `GET /deploy/aws/<id>` to follow its progress.
+pub struct JobQueue {
+    sender: mpsc::Sender<Job>,
+}
+
+impl JobQueue {
+    /// Spawn `workers` tokio tasks that provision or delete queued deployments
+    pub fn start(state: AppState, workers: usize, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for worker in 0..workers {
+            let state = state.clone();
+            let receiver = receiver.clone();
+            tokio::spawn(async move {
+                loop {
+                    let job = receiver.lock().await.recv().await;
+                    let Some(job) = job else {
+                        break;
+                    };
+                    let recorded = match &job.work {
+                        Work::Provision { plan, files } => {
+                            println!("Worker {} provisioning deployment {}", worker, job.id);
+                            run(&state, &job.id, plan, files).await
+                        }
+                        Work::Teardown { environment } => {
+                            println!("Worker {} deleting deployment {}", worker, job.id);
+                            delete(&state, &job.id, environment).await
+                        }
+                    };
+                    if let Err(e) = recorded {
+                        println!("Worker {} failed to record deployment: {}", worker, e);
+                    }
+                    // only release the slug once the outcome is recorded
+                    drop(job.lock);
+                }
+            });
+        }
+
+        Self { sender }
+    }
+
+    pub fn enqueue(&self, job: Job) -> Result<(), error::Error> {
+        self.sender.try_send(job).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => error::Error::new(
+                "DeployQueueFull",
+                Some("too many deployments are queued, try again later"),
+                503,
+            ),
+            mpsc::error::TrySendError::Closed(_) => {
+                error::Error::new("DeployQueueClosed", Some("no workers are running"), 500)
+            }
+        })
+    }
+}
+
+/// Provision a deployment, rolling back every completed step if one fails
+///
+/// Provisioning errors are recorded on the deployment; only failing to update
+/// the deployment store is returned.
+async fn run(state: &AppState, id: &str, plan: &Plan, files: &[File]) -> Result<(), error::Error> {
+    // the environment may have been removed from the config since the
+    // deployment was planned
+    let (cloud, environment) = match state.environment(&plan.environment) {
+        Ok(resolved) => resolved,
+        Err(e) => return record_failure(state, id, &e).await,
+    };
+    let mut saga = Saga::new();
+    let provisioned =
+        provision::execute(&state.store, cloud, environment, id, plan, files, &mut saga).await;
+    if let Err(e) = provisioned {
+        println!("Deployment {} failed: {}", id, e);
+        // undo every step that completed so the slug can be reused
+        let mut resources = state
+            .store
+            .get(id)
+            .await
+            .map(|r| r.resources)
+            .unwrap_or_default();
+        let rollback = saga.unwind(cloud, &environment.waits, &mut resources).await;
+        let rolled_back = rollback.iter().all(|r| r.succeeded());
+        state
+            .store
+            .update(id, |r| {
+                r.resources = resources;
+                r.phase = if rolled_back {
+                    DeploymentPhase::RolledBack
+                } else {
+                    DeploymentPhase::Failed
+                };
+                r.error = Some(e.to_string());
+                r.error_code = Some(e.err.clone());
+                r.retry_after = e.retry_after;
+                r.rollback = Some(rollback);
+            })
+            .await?;
+        return Ok(());
+    }
+
+    state
+        .store
+        .update(id, |r| r.phase = DeploymentPhase::Ready)
+        .await?;
+    Ok(())
+}
+
+/// Delete every resource recorded for a deployment
+///
+/// Resources that could not be deleted are kept on the deployment, which is
+/// marked `Failed` so the delete can be retried.
+async fn delete(state: &AppState, id: &str, environment: &str) -> Result<(), error::Error> {
+    let (cloud, environment) = match state.environment(environment) {
+        Ok(resolved) => resolved,
+        Err(e) => return record_failure(state, id, &e).await,
+    };
+    let mut resources = state
+        .store
+        .get(id)
+        .await
+        .map(|r| r.resources)
+        .unwrap_or_default();
+    let results = teardown::teardown(cloud, &environment.waits, &mut resources).await;
+    let failed = results.iter().filter(|r| !r.succeeded()).count();
+
+    state
+        .store
+        .update(id, |r| {
+            r.resources = resources;
+            if failed == 0 {
+                r.phase = DeploymentPhase::Deleted;
+                r.error = None;
+                r.error_code = None;
+                r.retry_after = None;
+            } else {
+                r.phase = DeploymentPhase::Failed;
+                r.error = Some(format!("{} resources could not be deleted", failed));
+                r.error_code = Some("TeardownIncomplete".to_string());
+            }
+            r.teardown = Some(results);
+        })
+        .await?;
+    Ok(())
+}
+
+/// Pick up the deployments that were in flight when the service stopped; the
+/// workers of `jobs` may already be running
+///
+/// Queued deployments and deletions are queued again, except for deployments
+/// with files, whose content was never persisted; those are marked `Failed`
+/// to be requested again. A deployment that was part way through provisioning
+/// cannot be resumed, since its saga only lived in memory; it is marked
+/// `Failed` and keeps its recorded resources so a `DELETE` removes them.
+pub async fn recover(state: &AppState, jobs: &JobQueue) {
+    // deployments a worker was provisioning when the service stopped; these
+    // are marked first, as the workers may take up the requeued deployments
+    // straight away and move them past `Pending` as well
+    for record in state.store.list_provisioning().await {
+        println!(
+            "Deployment {} was interrupted during {:?}",
+            record.id, record.phase
+        );
+        let failed = state
+            .store
+            .update(&record.id, |r| {
+                r.error = Some(format!(
+                    "provisioning was interrupted during {:?}; delete the deployment to remove its resources",
+                    r.phase
+                ));
+                r.phase = DeploymentPhase::Failed;
+                r.error_code = Some("ProvisioningInterrupted".to_string());
+            })
+            .await;
+        if let Err(e) = failed {
+            println!("Failed to record deployment {}: {}", record.id, e);
+        }
+    }
+
+    // deployments that were still queued when the service stopped
+    for record in state.store.list_by_phase(DeploymentPhase::Pending).await {
+        let id = record.id.clone();
+        let requeued = if record.plan.launch_template.files.is_empty() {
+            state
+                .slug_locks
+                .acquire(&record.input.deployment_slug)
+                .and_then(|lock| {
+                    jobs.enqueue(Job {
+                        id: record.id,
+                        work: Work::Provision {
+                            plan: Box::new(record.plan),
+                            files: vec![],
+                        },
+                        lock,
+                    })
+                })
+        } else {
+            Err(error::Error::new(
+                "FileContentLost",
+                Some("the content of the deployment's files is not kept across restarts; request the deployment again"),
+                500,
+            ))
+        };
+        if let Err(e) = requeued {
+            println!("Failed to requeue deployment {}: {}", id, e);
+            // nothing will pick the deployment up; record why so it can be deleted
+            let failed = state
+                .store
+                .update(&id, |r| {
+                    r.phase = DeploymentPhase::Failed;
+                    r.error = Some(e.to_string());
+                    r.error_code = Some(e.err.clone());
+                })
+                .await;
+            if let Err(e) = failed {
+                println!("Failed to record deployment {}: {}", id, e);
+            }
+        }
+    }
+
+    // deployments that were still being deleted when the service stopped
+    for record in state.store.list_by_phase(DeploymentPhase::Deleting).await {
+        let requeued = state
+            .slug_locks
+            .acquire(&record.input.deployment_slug)
+            .and_then(|lock| {
+                jobs.enqueue(Job {
+                    id: record.id.clone(),
+                    work: Work::Teardown {
+                        environment: record.plan.environment.clone(),
+                    },
+                    lock,
+                })
+            });
+        if let Err(e) = requeued {
+            println!(
+                "Failed to requeue deletion of deployment {}: {}",
+                record.id, e
+            );
+        }
+    }
+}
+
+/// Mark a deployment `Failed` with an error that stopped a worker before it
+/// touched the cloud
+async fn record_failure(state: &AppState, id: &str, e: &error::Error) -> Result<(), error::Error> {
+    println!("Deployment {} failed: {}", id, e);
+    state
+        .store
+        .update(id, |r| {
+            r.phase = DeploymentPhase::Failed;
+            r.error = Some(e.to_string());
+            r.error_code = Some(e.err.clone());
+        })
+        .await?;
+    Ok(())
+}
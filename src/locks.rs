@@ -0,0 +1,75 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use crate::error;
+
+/// Tracks which keys of one kind, such as deployment slugs, are being worked
+/// on, failing with that kind's conflict when one is locked twice
+#[derive(Clone)]
+pub struct Locks {
+    held: Arc<Mutex<HashSet<String>>>,
+    conflict: fn(&str) -> error::Error,
+}
+
+impl Locks {
+    /// Deployments of different slugs run in parallel, but only one create or
+    /// delete can be in flight for a given slug since they share resource
+    /// names.
+    pub fn slugs() -> Self {
+        Self::new(|slug| {
+            error::Error::new(
+                "DeploymentInProgress",
+                Some(&format!("deployment {} is already in progress", slug)),
+                409,
+            )
+        })
+    }
+
+    /// A fully qualified name is only served by one deployment, so only one
+    /// deployment at a time may be checked and recorded as its owner.
+    pub fn names() -> Self {
+        Self::new(|name| {
+            error::Error::new(
+                "DomainNameInProgress",
+                Some(&format!(
+                    "a deployment served at {} is already being created",
+                    name
+                )),
+                409,
+            )
+        })
+    }
+
+    fn new(conflict: fn(&str) -> error::Error) -> Self {
+        Self {
+            held: Arc::default(),
+            conflict,
+        }
+    }
+
+    /// Lock `key` until the returned guard is dropped, or fail with a 409 if
+    /// it is already locked
+    pub fn acquire(&self, key: &str) -> Result<LockGuard, error::Error> {
+        let mut held = self.held.lock().expect("lock set poisoned");
+        if !held.insert(key.to_string()) {
+            return Err((self.conflict)(key));
+        }
+        Ok(LockGuard {
+            held: self.held.clone(),
+            key: key.to_string(),
+        })
+    }
+}
+
+pub struct LockGuard {
+    held: Arc<Mutex<HashSet<String>>>,
+    key: String,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Ok(mut held) = self.held.lock() {
+            held.remove(&self.key);
+        }
+    }
+}
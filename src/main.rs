@@ -1,48 +1,200 @@
 #[macro_use]
 extern crate rocket;
-use aws_sdk_ec2::types::{LaunchTemplateInstanceMetadataTagsState, RequestLaunchTemplateData};
 use dotenv::dotenv;
 
-use error::OResult;
 use rocket::serde::json::Json;
 
+use rocket::response::status::Accepted;
 use rocket::State;
 use rocket_okapi::okapi::schemars::JsonSchema;
 use rocket_okapi::settings::UrlObject;
 
 use rocket_okapi::swagger_ui::make_swagger_ui;
 use rocket_okapi::{openapi, openapi_get_routes, rapidoc::*, swagger_ui::*};
-use rusoto_autoscaling::Autoscaling;
-use rusoto_core::Region;
-use rusoto_ec2::{Ec2, Ec2Client};
-use rusoto_elbv2::Elb;
-use rusoto_route53::Route53;
 
+use std::collections::HashMap;
 use std::env;
-use tokio::sync::Mutex;
+use std::sync::Arc;
 
 use rocket::serde::{Deserialize, Serialize};
+mod aws;
+mod cloud;
+mod config;
+mod deployment;
 mod error;
+#[cfg(test)]
+mod fake;
+mod files;
 mod handlers;
-
+mod jobs;
+mod locks;
+mod naming;
+mod plan;
+mod provision;
+mod retry;
+mod saga;
+mod secrets;
+mod store;
+mod teardown;
+#[cfg(test)]
+mod tests;
+mod validation;
+mod waiter;
+
+use aws::AwsProvider;
+use cloud::CloudProvider;
+use config::{Config, Environment};
+use deployment::{DeploymentPhase, DeploymentRecord};
+use jobs::{Job, JobQueue, Work};
+use locks::Locks;
+use plan::{Plan, Scheme};
 use uuid::Uuid;
 
-// let id = Uuid::new_v4();
-use aws_config::BehaviorVersion;
+#[derive(Clone)]
+struct AppState {
+    config: Arc<Config>,
+    /// The cloud each configured environment is provisioned in
+    providers: Arc<HashMap<String, Arc<dyn CloudProvider>>>,
+    store: Arc<store::DeploymentStore>,
+    slug_locks: Locks,
+    /// Held while a deployment is checked and recorded as the owner of its
+    /// fully qualified name
+    name_locks: Locks,
+    /// The domain of every environment's hosted zones, by zone id
+    zone_names: Arc<HashMap<String, String>>,
+}
 
-use std::collections::HashMap;
+impl AppState {
+    /// Look up the domain of every environment's hosted zones and check that
+    /// its load balancer subnets suit their scheme, once, so planning a
+    /// deployment doesn't have to call AWS
+    async fn new(
+        config: Config,
+        providers: HashMap<String, Arc<dyn CloudProvider>>,
+        store: store::DeploymentStore,
+    ) -> Result<Self, error::Error> {
+        let mut zone_names = HashMap::new();
+        for (name, environment) in &config.environments {
+            let cloud = providers.get(name).ok_or_else(|| {
+                error::Error::new(
+                    "UnknownEnvironment",
+                    Some(&format!("environment {} has no cloud provider", name)),
+                    500,
+                )
+            })?;
+            validation::check_subnets(
+                cloud.as_ref(),
+                Scheme::InternetFacing,
+                &environment.public_subnets,
+            )
+            .await?;
+            validation::check_subnets(
+                cloud.as_ref(),
+                Scheme::Internal,
+                &environment.private_subnets,
+            )
+            .await?;
+            let zones = std::iter::once(&environment.hosted_zone_id)
+                .chain(environment.private_hosted_zone_id.as_ref());
+            for zone in zones {
+                let zone_name = cloud
+                    .hosted_zone_name(zone)
+                    .await
+                    .map_err(|e| e.during("HostedZoneLookupFailed"))?;
+                zone_names.insert(zone.clone(), zone_name);
+            }
+        }
+        Ok(Self {
+            config: Arc::new(config),
+            providers: Arc::new(providers),
+            store: Arc::new(store),
+            slug_locks: Locks::slugs(),
+            name_locks: Locks::names(),
+            zone_names: Arc::new(zone_names),
+        })
+    }
 
-struct AppState {
-    ec2_client: Ec2Client,
-    as_client: rusoto_autoscaling::AutoscalingClient,
-    elb_client: rusoto_elbv2::ElbClient,
-    ec2_client_ng: aws_sdk_ec2::Client,
+    fn provider(&self, environment: &str) -> Result<&dyn CloudProvider, error::Error> {
+        self.providers
+            .get(environment)
+            .map(|p| p.as_ref())
+            .ok_or_else(|| {
+                error::Error::new(
+                    "UnknownEnvironment",
+                    Some(&format!("environment {} is not configured", environment)),
+                    422,
+                )
+            })
+    }
+
+    /// The cloud and configuration of an environment
+    fn environment(&self, name: &str) -> Result<(&dyn CloudProvider, &Environment), error::Error> {
+        let (_, environment) = self.config.environment(Some(name))?;
+        Ok((self.provider(name)?, environment))
+    }
+
+    /// Validate and plan `input` in the environment it asks for, without
+    /// calling AWS
+    fn plan(&self, input: &DeployAWSInput) -> Result<Plan, error::Error> {
+        validation::validate(input, &self.config)?;
+        let (name, environment) = self.config.environment(input.environment.as_deref())?;
+        let scheme = plan::scheme(input, environment)?;
+        let (_, hosted_zone_id) = plan::placement(environment, scheme)?;
+        let zone_name = self.zone_name(hosted_zone_id)?;
+        if input.https == Some(true) {
+            // certificates are validated through the public zone
+            let public_zone_name = self.zone_name(&environment.hosted_zone_id)?;
+            validation::check_certificate_domain(zone_name, public_zone_name)?;
+        }
+        Plan::new(input, &name, environment, zone_name)
+    }
+
+    fn zone_name(&self, hosted_zone_id: &str) -> Result<&str, error::Error> {
+        self.zone_names
+            .get(hosted_zone_id)
+            .map(|name| name.as_str())
+            .ok_or_else(|| {
+                error::Error::new(
+                    "HostedZoneLookupFailed",
+                    Some(&format!(
+                        "the domain of hosted zone {} is unknown",
+                        hosted_zone_id
+                    )),
+                    500,
+                )
+            })
+    }
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone)]
 struct File {
+    /// Never echoed back or persisted; instances receive it encrypted
+    #[serde(skip_serializing)]
     content: String,
+    /// Absolute path the file is written to
     path: String,
+    /// Octal file mode, `0600` by default
+    mode: Option<String>,
+    /// `user:group` owning the file, `root:root` by default
+    owner: Option<String>,
+}
+
+/// A file as it is persisted and echoed back, without its content
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+struct StoredFile {
+    path: String,
+    mode: Option<String>,
+    owner: Option<String>,
+}
+
+impl From<&File> for StoredFile {
+    fn from(file: &File) -> Self {
+        Self {
+            path: file.path.clone(),
+            mode: file.mode.clone(),
+            owner: file.owner.clone(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
@@ -50,510 +202,210 @@ struct Target {
     port: i64,
     health_check_path: Option<String>,
     health_check_enabled: Option<bool>,
+    /// Route requests whose path matches, e.g. `/api/*`, to this target
+    /// through a rule on the port 80 (or with `https`, 443) listener instead
+    /// of giving it a listener on its own port
+    path_pattern: Option<String>,
+    /// Like `path_pattern`, for requests to this host name, e.g.
+    /// `ws.example.com`; with both set a request must match both
+    host_header: Option<String>,
 }
 
-struct Tarn {
-    target: Target,
-    arn: String,
+impl Target {
+    /// Whether the target is reached through a listener rule
+    fn routed(&self) -> bool {
+        self.path_pattern.is_some() || self.host_header.is_some()
+    }
 }
 
+/// A deploy request; once accepted it is kept with [`StoredFile`]s in place of
+/// its files
 #[derive(Serialize, Deserialize, JsonSchema, Clone)]
-struct DeployAWSInput {
+struct DeployAWSInput<F = File> {
     flake_url: String,
     instance_type: String,
     deployment_slug: String, // i am the deployment slug @_\/
-    files: Option<Vec<File>>,
+    files: Option<Vec<F>>,
+    /// Prepended to the domain of the environment's hosted zone to give the
+    /// name the deployment is served at
     subdomain_prefix: String,
     min_size: Option<i64>,
     max_size: Option<i64>,
     targets: Option<Vec<Target>>,
     template_id: String,
+    /// The configured environment to deploy to, e.g. `staging` or `prod`;
+    /// the default environment is used when omitted
+    environment: Option<String>,
+    /// Only mark the deployment `Ready` once `min_size` targets pass their
+    /// health checks and the DNS record is in sync; `false` by default.
+    /// Provisioning always waits for `min_size` instances to be in service
+    /// in the auto scaling group; this adds the target health and DNS waits
+    /// on top of that. Targets with `health_check_enabled: false` count as
+    /// soon as they are registered
+    wait_for_healthy: Option<bool>,
+    /// Serve the first target over HTTPS on port 443 with an ACM certificate
+    /// for the deployment's domain name, redirecting HTTP on port 80 to it; the other
+    /// targets are served over HTTPS on their own port, or on 443 if they are
+    /// routed by `path_pattern` or `host_header`. `false` by default
+    https: Option<bool>,
+    /// Where clients may reach the deployment from, e.g. `["10.0.0.0/8"]`;
+    /// the environment's `ingress_cidrs` by default
+    ingress_cidrs: Option<Vec<String>>,
+    /// `internal` deployments are placed in the environment's private subnets
+    /// with their record in its private hosted zone; `internet-facing` by
+    /// default
+    scheme: Option<plan::Scheme>,
+    /// Route to the deployment through a host header rule on the
+    /// environment's shared load balancer, and its wildcard certificate,
+    /// instead of creating a load balancer; `false` by default. Needs a single
+    /// target without a `host_header`, and a single label `subdomain_prefix`;
+    /// `https` and `ingress_cidrs` are up to the shared load balancer
+    shared_load_balancer: Option<bool>,
+}
+
+impl DeployAWSInput {
+    /// The request as it is persisted and echoed back, without the content of
+    /// its files
+    fn stored(&self) -> DeployAWSInput<StoredFile> {
+        DeployAWSInput {
+            flake_url: self.flake_url.clone(),
+            instance_type: self.instance_type.clone(),
+            deployment_slug: self.deployment_slug.clone(),
+            files: self
+                .files
+                .as_ref()
+                .map(|files| files.iter().map(StoredFile::from).collect()),
+            subdomain_prefix: self.subdomain_prefix.clone(),
+            min_size: self.min_size,
+            max_size: self.max_size,
+            targets: self.targets.clone(),
+            template_id: self.template_id.clone(),
+            environment: self.environment.clone(),
+            wait_for_healthy: self.wait_for_healthy,
+            https: self.https,
+            ingress_cidrs: self.ingress_cidrs.clone(),
+            scheme: self.scheme,
+            shared_load_balancer: self.shared_load_balancer,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 struct DeployAWSOutput {
     id: String,
-    input: DeployAWSInput,
+    input: DeployAWSInput<StoredFile>,
+    /// The name the deployment is served at: `subdomain_prefix` in the
+    /// environment's hosted zone, e.g. `app.example.com`
+    fqdn: String,
 }
 
 impl DeployAWSOutput {
-    fn new(input: DeployAWSInput) -> Self {
+    fn new(input: DeployAWSInput<StoredFile>, fqdn: String) -> Self {
         Self {
             id: Uuid::new_v4().to_string(),
-            input: input,
+            input,
+            fqdn,
         }
     }
 }
 
-
-fn get_tag_data(
-    template_id: String,
-    flake_url: String,
-) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-    let mut tags = HashMap::new();
-
-    let turso_token = std::env::var("TURSO_TOKEN").map_err(
-        |e| error::Error::new("TursoTokenCreationFailed", Some(&e.to_string()), 500),
-    )?;
-    let file_encryption_key = std::env::var("FILE_ENCRYPTION_KEY").map_err(
-        |e| error::Error::new("FileEncryptionKeyCreationFailed", Some(&e.to_string()), 500),
-    )?;
-    tags.insert("turso_token".to_string(), turso_token);
-    tags.insert("file_encryption_key".to_string(), file_encryption_key);
-    tags.insert("template_id".to_string(), template_id);
-    tags.insert("flake_url".to_string(), flake_url);
-    Ok(tags)
-}
-
-/// Get instance ID from queue
+/// Create a deployment
+///
+/// Records the deployment and queues it for a background worker, which
+/// provisions the security groups, launch template, auto scaling group, target
+/// groups, load balancer, listeners and Route53 alias records, waiting for the
+/// load balancer to become active and for instances to be in service, within
+/// the environment's configured timeouts. Instances only accept traffic from
+/// the load balancer. With `shared_load_balancer`, a rule on the environment's
+/// shared load balancer takes the place of its own. Returns `202 Accepted`
+/// straight away; poll `GET /deploy/aws/<id>` to follow the deployment. If a
+/// step fails, every step that completed before it is rolled back.
 ///
-/// Retrieves the next available EC2 instance ID from the queue.
+/// Deployments of different slugs are provisioned in parallel; a slug that is
+/// already being deployed or still has a live deployment is rejected with 409,
+/// as is a name another live deployment is served at.
+/// Invalid requests are rejected with 422 before anything is recorded, listing
+/// every invalid field.
 #[openapi]
 #[post("/deploy/aws/create", data = "<input>")]
 async fn deploy_aws_create(
-    state: &State<Mutex<AppState>>,
+    state: &State<AppState>,
+    jobs: &State<JobQueue>,
     input: Json<DeployAWSInput>,
-) -> OResult<DeployAWSOutput> {
-    let state = state.lock().await;
-    let ec2_client = &state.ec2_client;
-    println!("Input: {:?}", input.0.clone().deployment_slug);
-    let output = DeployAWSOutput::new(input.0.clone());
-
-    let tags = get_tag_data(input.template_id.clone(), input.flake_url.clone())
-        .map_err(|e| error::Error::new("TagDataCreationFailed", Some(&e.to_string()), 500))?;
-
-    // instead create launch template with ec2_client_ng b/c that has access to
-    // the latest version of the api
-    state
-        .ec2_client_ng
-        .create_launch_template()
-        .set_launch_template_name(Some(input.deployment_slug.clone()))
-        .set_launch_template_data(Some(
-            RequestLaunchTemplateData::builder()
-                .instance_type(aws_sdk_ec2::types::InstanceType::T3Small)
-                .image_id("ami-0d1d97987c98945a7")
-                .set_metadata_options(Some(
-                    aws_sdk_ec2::types::LaunchTemplateInstanceMetadataOptionsRequest::builder()
-                        .set_instance_metadata_tags(Some(
-                            LaunchTemplateInstanceMetadataTagsState::Enabled,
-                        ))
-                        .build(),
-                ))
-                .set_tag_specifications(Some(vec![
-                    aws_sdk_ec2::types::LaunchTemplateTagSpecificationRequest::builder()
-                        .set_resource_type(Some(aws_sdk_ec2::types::ResourceType::Instance))
-                        .set_tags(Some(
-                            tags.iter()
-                                .map(|(k, v)| {
-                                    aws_sdk_ec2::types::Tag::builder()
-                                        .set_key(Some(k.clone()))
-                                        .set_value(Some(v.clone()))
-                                        .build()
-                                })
-                                .collect(),
-                        ))
-                        .build(),
-                ]))
-                .set_block_device_mappings(Some(vec![
-                    aws_sdk_ec2::types::LaunchTemplateBlockDeviceMappingRequest::builder()
-                        .device_name("/dev/sda1")
-                        .ebs(
-                            aws_sdk_ec2::types::LaunchTemplateEbsBlockDeviceRequest::builder()
-                                .volume_size(80)
-                                .volume_type(aws_sdk_ec2::types::VolumeType::Gp2)
-                                .delete_on_termination(true)
-                                .build(),
-                        )
-                        .build(),
-                ]))
-                .build(),
-        ))
-        .send()
-        .await
-        .map_err(|e| {
-            error::Error::new("LaunchTemplateCreationFailed", Some(&e.to_string()), 500)
-        })?;
-
-    let as_client = &state.as_client;
-
-    // create auto scaling group
-    let create_asg_req = rusoto_autoscaling::CreateAutoScalingGroupType {
-        auto_scaling_group_name: input.deployment_slug.clone(),
-        launch_template: Some(rusoto_autoscaling::LaunchTemplateSpecification {
-            launch_template_name: Some(input.deployment_slug.clone()),
-            ..Default::default()
-        }),
-        min_size: input.min_size.unwrap_or(1),
-        max_size: input.max_size.unwrap_or(1),
-        vpc_zone_identifier: Some("subnet-0c762bc5239b282a0".to_string()),
-        // availability_zones: Some(vec!["us-west-1a".to_string(), "us-west-1c".to_string()]),
-        // desired_capacity: 1,
-        // Add other parameters here as needed
-        ..Default::default()
-    };
-
-    let resp = as_client.create_auto_scaling_group(create_asg_req).await;
-
-    match resp {
-        Ok(output) => {
-            println!("Auto scaling group created: {:?}", output);
-        }
-        Err(e) => {
-            return Err(error::Error::new(
-                "AutoScalingGroupCreationFailed",
-                Some(&e.to_string()),
-                500,
-            ));
-        }
+) -> Result<Accepted<Json<DeployAWSOutput>>, error::Error> {
+    let plan = state.plan(&input)?;
+    let lock = state.slug_locks.acquire(&input.deployment_slug)?;
+    if let Some(existing) = state.store.find_by_slug(&input.deployment_slug).await {
+        return Err(error::Error::new(
+            "DeploymentSlugTaken",
+            Some(&format!(
+                "deployment {} already uses slug {}",
+                existing.id, input.deployment_slug
+            )),
+            409,
+        ));
     }
-
-    let vpc_id = "vpc-031c620b47a9ea885".to_string();
-    let public_subnets = vec![
-        "subnet-040ebc679c54ecf38".to_string(),
-        "subnet-0e22657a6f50a3235".to_string(),
-    ];
-    // create target group
-    let elb_client = &state.elb_client;
-
-    let create_target_group_reqs = input
-        .targets
-        .as_ref()
-        .unwrap_or(&vec![Target {
-            port: 8000,
-            ..Default::default()
-        }])
-        .iter()
-        .map(|t| {
-            rusoto_elbv2::CreateTargetGroupInput {
-                name: input.deployment_slug.clone(),
-                protocol: Some("HTTP".to_string()),
-                port: Some(t.port),
-                vpc_id: Some(vpc_id.clone()),
-                health_check_path: t.health_check_path.clone(),
-                health_check_enabled: t.health_check_enabled.clone(),
-                // Add other parameters here as needed
-                ..Default::default()
-            }
-        })
-        .collect::<Vec<rusoto_elbv2::CreateTargetGroupInput>>();
-
-    let mut target_group_arns = vec![];
-
-    for req in create_target_group_reqs {
-        let resp = elb_client.create_target_group(req).await;
-
-        match resp {
-            Ok(output) => {
-                println!("Target group created: {:?}", output);
-                if let Some(target_groups) = output.target_groups {
-                    target_group_arns.push(target_groups[0].target_group_arn.clone());
-                }
-            }
-            Err(e) => {
-                return Err(error::Error::new(
-                    "TargetGroupCreationFailed",
-                    Some(&e.to_string()),
-                    500,
-                ));
-            }
-        }
+    // the name is only locked until the deployment is recorded as its owner
+    let _name_lock = state.name_locks.acquire(&plan.record_set.name)?;
+    if let Some(existing) = state.store.find_by_name(&plan.record_set.name).await {
+        return Err(error::Error::new(
+            "DomainNameTaken",
+            Some(&format!(
+                "deployment {} is already served at {}",
+                existing.id, plan.record_set.name
+            )),
+            409,
+        ));
     }
+    let output = DeployAWSOutput::new(input.stored(), plan.record_set.name.clone());
 
-    let tarns = input
-        .targets
-        .as_ref()
-        .unwrap_or(&vec![Target {
-            port: 8000,
-            ..Default::default()
-        }])
-        .iter()
-        .zip(target_group_arns.iter())
-        .map(|(t, arn)| Tarn {
-            target: t.clone(),
-            arn: arn.clone().unwrap(),
-        })
-        .collect::<Vec<Tarn>>();
-
-    // create security groups
-    let create_sg_req = rusoto_ec2::CreateSecurityGroupRequest {
-        description: "Security group for the deployment".to_string(),
-        group_name: input.deployment_slug.clone(),
-        vpc_id: Some(vpc_id.clone()),
-        // Add other parameters here as needed
-        ..Default::default()
-    };
-
-    let resp = ec2_client.create_security_group(create_sg_req).await;
-    let sg_id = match resp {
-        Ok(output) => {
-            println!("Security group created: {:?}", output);
-            output.group_id.clone()
-        }
-        Err(e) => {
-            return Err(error::Error::new(
-                "SecurityGroupCreationFailed",
-                Some(&e.to_string()),
-                500,
-            ));
-        }
-    };
-    // rusoto_ec2::AuthorizeSecurityGroupIngressRequest {
-    //     group_id:sg_id.clone(),
-    //     // Add other parameters here as needed
-    //     ..Default::default()
-    // };
-
-    let authorize_sg_reqs = input
-        .targets
-        .as_ref()
-        .unwrap_or(&vec![Target {
-            port: 8000,
-            ..Default::default()
-        }])
-        .iter()
-        .map(|t| rusoto_ec2::AuthorizeSecurityGroupIngressRequest {
-            group_id: sg_id.clone(),
-            from_port: Some(t.port),
-            to_port: Some(t.port),
-            ip_protocol: Some("TCP".to_string()),
-            cidr_ip: Some("0.0.0.0/0".to_string()),
-            ..Default::default()
-        })
-        .collect::<Vec<rusoto_ec2::AuthorizeSecurityGroupIngressRequest>>();
-
-    for req in authorize_sg_reqs {
-        let resp = ec2_client.authorize_security_group_ingress(req).await;
-
-        match resp {
-            Ok(output) => {
-                println!("Security group ingress rules added: {:?}", output);
-            }
-            Err(e) => {
-                return Err(error::Error::new(
-                    "SecurityGroupIngressRulesAdditionFailed",
-                    Some(&e.to_string()),
-                    500,
-                ));
-            }
-        }
-    }
-
-    // create load balancer
-    let create_lb_req = rusoto_elbv2::CreateLoadBalancerInput {
-        name: input.deployment_slug.clone(),
-        subnets: Some(public_subnets),
-        security_groups: Some(vec![sg_id.expect("sg should be set")]), // todo add security group
-        // Add other parameters here as needed
-        ..Default::default()
-    };
-
-    let resp = elb_client.create_load_balancer(create_lb_req).await;
-
-    let (lb_dns, load_balancer_arn) = match resp {
-        Ok(output) => {
-            println!("Load balancer created: {:?}", output);
-            let lb_dns = output.load_balancers.as_ref().unwrap()[0].dns_name.clone();
-            let load_balancer_arn = output.load_balancers.as_ref().unwrap()[0]
-                .load_balancer_arn
-                .clone();
-            (lb_dns, load_balancer_arn)
-        }
-        Err(e) => {
-            return Err(error::Error::new(
-                "LoadBalancerCreationFailed",
-                Some(&e.to_string()),
-                500,
-            ));
-        }
+    state
+        .store
+        .insert(DeploymentRecord::new(
+            output.id.clone(),
+            input.stored(),
+            plan.clone(),
+        ))
+        .await?;
+
+    let job = Job {
+        id: output.id.clone(),
+        work: Work::Provision {
+            plan: Box::new(plan),
+            files: input.into_inner().files.unwrap_or_default(),
+        },
+        lock,
     };
-
-    // wait for load balancer to be ready
-
-    for _ in 0..100 {
-        let resp = elb_client
-            .describe_load_balancers(rusoto_elbv2::DescribeLoadBalancersInput {
-                load_balancer_arns: Some(vec![load_balancer_arn.clone().unwrap()]),
-                ..Default::default()
+    if let Err(e) = jobs.enqueue(job) {
+        state
+            .store
+            .update(&output.id, |r| {
+                r.phase = DeploymentPhase::Failed;
+                r.error = Some(e.to_string());
+                r.error_code = Some(e.err.clone());
             })
-            .await;
-
-        match resp {
-            Ok(output) => {
-                let state = output.load_balancers.as_ref().unwrap()[0]
-                    .state
-                    .as_ref()
-                    .unwrap();
-                if let Some(code) = state.code.as_ref() {
-                    if code == "active" {
-                        break;
-                    }
-                }
-            }
-            Err(e) => {
-                return Err(error::Error::new(
-                    "LoadBalancerStateCheckFailed",
-                    Some(&e.to_string()),
-                    500,
-                ));
-            }
-        }
-        // sleep for 3 seconds
-        tokio::time::sleep(std::time::Duration::from_secs(3)).await;
-    }
-
-    // pub struct Certificate {
-    //     /// <p>The Amazon Resource Name (ARN) of the certificate.</p>
-    //     pub certificate_arn: Option<String>,
-    //     /// <p>Indicates whether the certificate is the default certificate. Do not set this value when specifying a certificate as an input. This value is not included in the output when describing a listener, but is included when describing listener certificates.</p>
-    //     pub is_default: Option<bool>,
-    // }
-
-    // create listener
-
-    let create_listener_reqs = tarns
-        .iter()
-        .map(|tarn| {
-            rusoto_elbv2::CreateListenerInput {
-                default_actions: vec![rusoto_elbv2::Action {
-                    target_group_arn: Some(tarn.arn.clone()),
-                    type_: "forward".to_string(),
-                    ..Default::default()
-                }],
-                load_balancer_arn: load_balancer_arn.clone().unwrap(),
-                port: Some(tarn.target.port),
-                protocol: Some("HTTP".to_string()),
-                // Add other parameters here as needed
-                ..Default::default()
-            }
-        })
-        .collect::<Vec<rusoto_elbv2::CreateListenerInput>>();
-
-    for req in create_listener_reqs {
-        let resp = elb_client.create_listener(req).await;
-
-        match resp {
-            Ok(output) => {
-                println!("Listener created: {:?}", output);
-            }
-            Err(e) => {
-                return Err(error::Error::new(
-                    "ListenerCreationFailed",
-                    Some(&e.to_string()),
-                    500,
-                ));
-            }
-        }
-    }
-
-    // attach target group to auto scaling group
-    let attach_tg_req = rusoto_autoscaling::AttachLoadBalancerTargetGroupsType {
-        auto_scaling_group_name: input.deployment_slug.clone(),
-        target_group_ar_ns: target_group_arns
-            .iter()
-            .map(|t| t.clone().unwrap())
-            .collect::<Vec<String>>(),
-        // Add other parameters here as needed
-        ..Default::default()
-    };
-
-    let resp = as_client
-        .attach_load_balancer_target_groups(attach_tg_req)
-        .await;
-
-    match resp {
-        Ok(output) => {
-            println!("Target group attached: {:?}", output);
-        }
-        Err(e) => {
-            return Err(error::Error::new(
-                "TargetGroupAttachFailed",
-                Some(&e.to_string()),
-                500,
-            ));
-        }
-    }
-
-    // create an A record in aws to match load balancer dns name to subdomain
-
-    // create a route53 record set
-    // {"err":"RecordSetCreationFailed","msg":"Request ID: Some(\"c203136a-5083-4d3b-8b3c-989c978cd68a\") Body: <?xml version=\"1.0\"?>\n<ErrorResponse xmlns=\"https://route53.amazonaws.com/doc/2013-04-01/\"><Error><Type>Sender</Type><Code>SignatureDoesNotMatch</Code><Message>Credential should be scoped to a valid region. </Message></Error><RequestId>c203136a-5083-4d3b-8b3c-989c978cd68a</RequestId></ErrorResponse>"}%
-    let record_set = rusoto_route53::ResourceRecordSet {
-        name: input.subdomain_prefix.clone(),
-        type_: "CNAME".to_string(),
-        ttl: Some(300),
-        region: Some("us-west-1".to_string()), // todo get region from ec2 client
-        resource_records: Some(vec![rusoto_route53::ResourceRecord {
-            value: lb_dns.clone().unwrap(),
-        }]),
-
-        // Add other parameters here as needed
-        ..Default::default()
-    };
-
-    let change = rusoto_route53::Change {
-        action: "CREATE".to_string(),
-        resource_record_set: record_set,
-    };
-
-    let change_batch = rusoto_route53::ChangeBatch {
-        changes: vec![change],
-        comment: None,
-    };
-
-    let change_resource_record_sets_req = rusoto_route53::ChangeResourceRecordSetsRequest {
-        change_batch: change_batch,
-        hosted_zone_id: "Z03309493AGZOVY2IU47X".to_string(),
-    };
-
-    let route53_client = rusoto_route53::Route53Client::new(Region::default());
-
-    let resp = route53_client
-        .change_resource_record_sets(change_resource_record_sets_req)
-        .await;
-
-    match resp {
-        Ok(output) => {
-            println!("Record set created: {:?}", output);
-        }
-        Err(e) => {
-            return Err(error::Error::new(
-                "RecordSetCreationFailed",
-                Some(&e.to_string()),
-                500,
-            ));
-        }
+            .await?;
+        return Err(e);
     }
 
-    Ok(Json(output))
+    Ok(Accepted(Json(output)))
 }
 
-#[rocket::main]
-async fn main() {
-    dotenv().ok();
-
-    let ec2_client = Ec2Client::new(Region::default());
-    let as_client = rusoto_autoscaling::AutoscalingClient::new(Region::default());
-    let elb_client = rusoto_elbv2::ElbClient::new(Region::default());
-    let config = aws_config::load_defaults(BehaviorVersion::v2023_11_09()).await;
-
-    let ec2_client_ng = aws_sdk_ec2::Client::new(&config);
-
-    let _ = rocket::build()
-        .configure(rocket::Config {
-            address: "0.0.0.0".parse().expect("valid IP address"),
-            port: 8000,
-            ..rocket::Config::default()
-        })
-        .manage(Mutex::new(AppState {
-            ec2_client,
-            as_client,
-            elb_client,
-            ec2_client_ng,
-        }))
-        .mount("/", openapi_get_routes![
-            deploy_aws_create,
-            handlers::log::log,
-            ])
+/// The service with its routes and state, but not yet configured or launched
+fn rocket(state: AppState, jobs: JobQueue) -> rocket::Rocket<rocket::Build> {
+    rocket::build()
+        .manage(state)
+        .manage(jobs)
+        .mount(
+            "/",
+            openapi_get_routes![
+                deploy_aws_create,
+                handlers::deploy::deploy_aws_status,
+                handlers::deploy::deploy_aws_delete,
+                handlers::deploy::deploy_aws_plan,
+                handlers::log::log,
+            ],
+        )
         .mount(
             "/swagger-ui/",
             make_swagger_ui(&SwaggerUIConfig {
@@ -576,6 +428,52 @@ async fn main() {
                 ..Default::default()
             }),
         )
+}
+
+#[rocket::main]
+async fn main() {
+    dotenv().ok();
+
+    let config = match Config::load(&Config::figment()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut providers: HashMap<String, Arc<dyn CloudProvider>> = HashMap::new();
+    for (name, environment) in &config.environments {
+        providers.insert(name.clone(), Arc::new(AwsProvider::new(environment).await));
+    }
+
+    let store_dir = env::var("DEPLOYMENT_STORE_DIR").unwrap_or_else(|_| "deployments".to_string());
+    let store = store::DeploymentStore::open(store_dir)
+        .await
+        .expect("failed to open deployment store");
+
+    let state = match AppState::new(config, providers, store).await {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("Invalid environment: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let workers = env::var("DEPLOY_WORKERS")
+        .ok()
+        .and_then(|w| w.parse().ok())
+        .unwrap_or(4);
+    let jobs = JobQueue::start(state.clone(), workers, 100);
+
+    jobs::recover(&state, &jobs).await;
+
+    let _ = rocket(state, jobs)
+        .configure(rocket::Config {
+            address: "0.0.0.0".parse().expect("valid IP address"),
+            port: 8000,
+            ..rocket::Config::default()
+        })
         .launch()
         .await;
 }
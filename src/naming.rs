@@ -0,0 +1,141 @@
+use sha2::{Digest, Sha256};
+
+use crate::error;
+
+/// Longest deployment slug accepted
+pub const MAX_SLUG_LENGTH: usize = 63;
+/// Load balancer and target group names are limited to 32 characters
+const MAX_ELB_NAME_LENGTH: usize = 32;
+/// Launch template names are limited to 128 characters
+const MAX_LAUNCH_TEMPLATE_NAME_LENGTH: usize = 128;
+/// Auto scaling group and security group names are limited to 255 characters
+const MAX_NAME_LENGTH: usize = 255;
+/// DNS names are limited to 253 characters
+const MAX_DOMAIN_NAME_LENGTH: usize = 253;
+/// Hex digits of the hash inserted into shortened names
+const HASH_LENGTH: usize = 8;
+
+/// Prefixes AWS reserves for load balancer and security group names
+const RESERVED_PREFIXES: [&str; 2] = ["internal-", "sg-"];
+
+fn invalid_slug(slug: &str, reason: &str) -> error::Error {
+    error::Error::new(
+        "InvalidDeploymentSlug",
+        Some(&format!("deployment slug {:?} {}", slug, reason)),
+        422,
+    )
+}
+
+/// Check that `slug` can be part of every resource name
+///
+/// Slugs are lowercase letters, digits and hyphens, start and end with a
+/// letter or digit, and are at most [`MAX_SLUG_LENGTH`] characters.
+pub fn validate_slug(slug: &str) -> Result<(), error::Error> {
+    if slug.is_empty() || slug.len() > MAX_SLUG_LENGTH {
+        return Err(invalid_slug(
+            slug,
+            &format!("must be between 1 and {} characters", MAX_SLUG_LENGTH),
+        ));
+    }
+    if !slug
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err(invalid_slug(
+            slug,
+            "may only contain lowercase letters, digits and hyphens",
+        ));
+    }
+    if slug.starts_with('-') || slug.ends_with('-') {
+        return Err(invalid_slug(slug, "must not start or end with a hyphen"));
+    }
+    if let Some(prefix) = RESERVED_PREFIXES.iter().find(|p| slug.starts_with(*p)) {
+        return Err(invalid_slug(
+            slug,
+            &format!("must not start with {:?}", prefix),
+        ));
+    }
+    Ok(())
+}
+
+/// The fully qualified name of a deployment, `<subdomain_prefix>.<zone>`,
+/// in lowercase as DNS names are case-insensitive
+pub fn fqdn(subdomain_prefix: &str, zone_name: &str) -> Result<String, error::Error> {
+    let fqdn = format!(
+        "{}.{}",
+        subdomain_prefix.trim_end_matches('.'),
+        zone_name.trim_end_matches('.')
+    )
+    .to_ascii_lowercase();
+    if fqdn.len() > MAX_DOMAIN_NAME_LENGTH {
+        return Err(error::Error::invalid_fields(vec![error::FieldError {
+            field: "subdomain_prefix".to_string(),
+            code: "TooLong".to_string(),
+            message: format!(
+                "{} is longer than the {} characters a domain name may have",
+                fqdn, MAX_DOMAIN_NAME_LENGTH
+            ),
+        }]));
+    }
+    Ok(fqdn)
+}
+
+/// `<slug>-<suffix>`, or when that is longer than `max_length`, the slug cut
+/// short followed by a hash of the full name: `<slug prefix>-<hash>-<suffix>`
+///
+/// The same slug and suffix always give the same name, and two names only
+/// collide if their full names' hashes do.
+fn name(slug: &str, suffix: &str, max_length: usize) -> String {
+    let full = format!("{}-{}", slug, suffix);
+    if full.len() <= max_length {
+        return full;
+    }
+    let hash = format!("{:x}", Sha256::digest(full.as_bytes()));
+    let keep = max_length - suffix.len() - HASH_LENGTH - 2;
+    format!(
+        "{}-{}-{}",
+        slug[..keep].trim_end_matches('-'),
+        &hash[..HASH_LENGTH],
+        suffix
+    )
+}
+
+/// The names of every resource created for a deployment
+pub struct Names<'a> {
+    slug: &'a str,
+}
+
+impl<'a> Names<'a> {
+    /// Names for the deployment `slug`, which is validated first
+    pub fn new(slug: &'a str) -> Result<Self, error::Error> {
+        validate_slug(slug)?;
+        Ok(Self { slug })
+    }
+
+    pub fn launch_template(&self) -> String {
+        name(self.slug, "lt", MAX_LAUNCH_TEMPLATE_NAME_LENGTH)
+    }
+
+    pub fn auto_scaling_group(&self) -> String {
+        name(self.slug, "asg", MAX_NAME_LENGTH)
+    }
+
+    /// One target group per target port, e.g. `slug-tg-8080`
+    pub fn target_group(&self, port: i64) -> String {
+        name(self.slug, &format!("tg-{}", port), MAX_ELB_NAME_LENGTH)
+    }
+
+    /// The group of the load balancer, open to clients
+    pub fn load_balancer_security_group(&self) -> String {
+        name(self.slug, "lb-sg", MAX_NAME_LENGTH)
+    }
+
+    /// The group of the instances, only open to the load balancer
+    pub fn instance_security_group(&self) -> String {
+        name(self.slug, "instance-sg", MAX_NAME_LENGTH)
+    }
+
+    pub fn load_balancer(&self) -> String {
+        name(self.slug, "lb", MAX_ELB_NAME_LENGTH)
+    }
+}
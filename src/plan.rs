@@ -0,0 +1,475 @@
+use std::collections::BTreeMap;
+
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::okapi::schemars::JsonSchema;
+
+use crate::config::{Environment, SharedLoadBalancerConfig};
+use crate::error;
+use crate::files::{self, PlannedFile};
+use crate::naming::{self, Names};
+use crate::secrets::SecretsPlan;
+use crate::{DeployAWSInput, Target};
+
+/// Where HTTPS deployments serve their first target, and redirect from
+pub const HTTPS_PORT: i64 = 443;
+pub const HTTP_PORT: i64 = 80;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct LaunchTemplatePlan {
+    pub name: String,
+    pub instance_type: String,
+    pub image_id: String,
+    pub device_name: String,
+    pub volume_size: i32,
+    pub template_id: String,
+    pub flake_url: String,
+    /// Files written to every instance at boot; they are encrypted into the
+    /// template's user data when it is created
+    pub files: Vec<PlannedFile>,
+    /// Name or ARN of the instance profile instances read their secrets with
+    pub instance_profile: Option<String>,
+    /// Name of the security group set on the instances' network interface
+    pub security_group: String,
+    /// Instance tags; these never hold secrets, only where to find them
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct AutoScalingGroupPlan {
+    pub name: String,
+    pub launch_template_name: String,
+    pub min_size: i64,
+    pub max_size: i64,
+    pub subnets: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct TargetGroupPlan {
+    pub name: String,
+    pub protocol: String,
+    pub port: i64,
+    pub vpc_id: String,
+    pub health_check_path: Option<String>,
+    pub health_check_enabled: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct IngressRulePlan {
+    pub protocol: String,
+    pub from_port: i64,
+    pub to_port: i64,
+    pub source: IngressSource,
+}
+
+/// Where traffic an ingress rule allows may come from
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IngressSource {
+    /// An IPv4 or IPv6 CIDR block
+    Cidr(String),
+    /// Members of the named security group the deployment creates
+    SecurityGroup(String),
+    /// Members of an existing security group, by id
+    SecurityGroupId(String),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SecurityGroupPlan {
+    pub name: String,
+    pub description: String,
+    pub vpc_id: String,
+    pub ingress: Vec<IngressRulePlan>,
+}
+
+/// Who a deployment's load balancer is reachable by
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scheme {
+    /// Placed in public subnets, with its record in the public hosted zone
+    #[default]
+    InternetFacing,
+    /// Placed in private subnets, with its record in the private hosted zone
+    Internal,
+}
+
+impl Scheme {
+    /// The name ELB uses for the scheme
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::InternetFacing => "internet-facing",
+            Scheme::Internal => "internal",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct LoadBalancerPlan {
+    pub name: String,
+    #[serde(default)]
+    pub scheme: Scheme,
+    pub subnets: Vec<String>,
+}
+
+/// The subnets a load balancer of `scheme` is placed in and the hosted zone
+/// its record is created in, failing if `environment` has none for it
+pub fn placement(
+    environment: &Environment,
+    scheme: Scheme,
+) -> Result<(&[String], &str), error::Error> {
+    match scheme {
+        Scheme::InternetFacing => Ok((&environment.public_subnets, &environment.hosted_zone_id)),
+        Scheme::Internal => match &environment.private_hosted_zone_id {
+            Some(zone) if !environment.private_subnets.is_empty() => {
+                Ok((&environment.private_subnets, zone))
+            }
+            _ => Err(error::Error::invalid_fields(vec![error::FieldError {
+                field: "scheme".to_string(),
+                code: "SchemeNotConfigured".to_string(),
+                message: "internal deployments need the environment's private_subnets and private_hosted_zone_id".to_string(),
+            }])),
+        },
+    }
+}
+
+/// The scheme of the load balancer a deployment is reached through: the
+/// shared load balancer's if it joins it, otherwise the one requested
+pub fn scheme(input: &DeployAWSInput, environment: &Environment) -> Result<Scheme, error::Error> {
+    let shared = shared_load_balancer(input, environment)?;
+    Ok(shared.map_or(input.scheme.unwrap_or_default(), |s| s.scheme))
+}
+
+/// What a listener does with the requests it receives
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerAction {
+    /// Forward to the named target group
+    Forward(String),
+    /// Redirect to HTTPS on the given port
+    RedirectToHttps(i64),
+    /// Respond with 404 to the requests none of the listener's rules match
+    NotFound,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct ListenerPlan {
+    /// `HTTP` or `HTTPS`; HTTPS listeners use the deployment's certificate
+    pub protocol: String,
+    pub port: i64,
+    /// What is done with requests no rule matches
+    pub action: ListenerAction,
+    /// Tried in order before `action`
+    #[serde(default)]
+    pub rules: Vec<ListenerRulePlan>,
+}
+
+/// An ACM certificate for the deployment's domain, validated through DNS
+/// records in the environment's public hosted zone, which ACM can resolve
+/// even for internal deployments
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct CertificatePlan {
+    pub domain_name: String,
+    pub hosted_zone_id: String,
+}
+
+/// Routes the requests matching every condition that is set to a target group
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct ListenerRulePlan {
+    pub host_header: Option<String>,
+    pub path_pattern: Option<String>,
+    pub target_group: String,
+}
+
+/// The environment's shared load balancer, which the deployment joins with a
+/// listener rule instead of creating a load balancer of its own
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SharedLoadBalancerPlan {
+    pub arn: String,
+    pub listener_arn: String,
+    pub rule: ListenerRulePlan,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct TargetGroupAttachmentPlan {
+    pub auto_scaling_group: String,
+    pub target_groups: Vec<String>,
+}
+
+/// The Route53 changes; the records are aliases of the load balancer, which
+/// is only known once it exists
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct RecordSetPlan {
+    pub hosted_zone_id: String,
+    /// `UPSERT`, so redeploying to the same name takes it over
+    pub action: String,
+    /// The deployment's fully qualified name
+    pub name: String,
+    /// One alias record of each type; only `A`, as load balancers are
+    /// created IPv4 only
+    pub record_types: Vec<String>,
+}
+
+/// Every resource a deployment creates, in the order they are created
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct Plan {
+    /// The configured environment the deployment is provisioned in
+    pub environment: String,
+    pub secrets: SecretsPlan,
+    /// Requested first, as validation takes a while; only with `https`
+    pub certificate: Option<CertificatePlan>,
+    /// Allows the listener ports from the request's `ingress_cidrs`, or the
+    /// environment's; unset on the shared load balancer
+    pub load_balancer_security_group: Option<SecurityGroupPlan>,
+    /// Allows the target ports from the load balancer's group only
+    pub instance_security_group: SecurityGroupPlan,
+    pub launch_template: LaunchTemplatePlan,
+    pub auto_scaling_group: AutoScalingGroupPlan,
+    pub target_groups: Vec<TargetGroupPlan>,
+    /// Unset on the shared load balancer
+    pub load_balancer: Option<LoadBalancerPlan>,
+    pub listeners: Vec<ListenerPlan>,
+    /// Set instead of `load_balancer` with `shared_load_balancer`
+    #[serde(default)]
+    pub shared_load_balancer: Option<SharedLoadBalancerPlan>,
+    pub target_group_attachment: TargetGroupAttachmentPlan,
+    pub record_set: RecordSetPlan,
+    /// Only mark the deployment `Ready` once `min_size` targets are healthy
+    /// in every target group and the record set change is in sync
+    #[serde(default)]
+    pub wait_for_healthy: bool,
+}
+
+impl Plan {
+    /// Translate a validated deploy request into the resources it creates,
+    /// without calling AWS; `zone_name` is the domain of the environment's
+    /// hosted zone
+    pub fn new(
+        input: &DeployAWSInput,
+        environment_name: &str,
+        environment: &Environment,
+        zone_name: &str,
+    ) -> Result<Self, error::Error> {
+        let fqdn = naming::fqdn(&input.subdomain_prefix, zone_name)?;
+        let shared = shared_load_balancer(input, environment)?;
+        let scheme = scheme(input, environment)?;
+        let (load_balancer_subnets, hosted_zone_id) = placement(environment, scheme)?;
+        let ingress_cidrs = input
+            .ingress_cidrs
+            .as_ref()
+            .unwrap_or(&environment.ingress_cidrs);
+        let names = Names::new(&input.deployment_slug)?;
+        let secrets = SecretsPlan::new(&environment.secrets, &input.deployment_slug);
+        let tags = BTreeMap::from([
+            ("template_id".to_string(), input.template_id.clone()),
+            ("flake_url".to_string(), input.flake_url.clone()),
+            (
+                "secrets_backend".to_string(),
+                secrets.backend.as_str().to_string(),
+            ),
+            ("secrets_reference".to_string(), secrets.reference.clone()),
+        ]);
+        let planned_files = files::plan(input.files.as_deref().unwrap_or_default())?;
+        let targets = input.targets.clone().unwrap_or_else(|| {
+            vec![Target {
+                port: 8000,
+                ..Default::default()
+            }]
+        });
+        let target_groups = targets
+            .iter()
+            .map(|t| TargetGroupPlan {
+                name: names.target_group(t.port),
+                protocol: "HTTP".to_string(),
+                port: t.port,
+                vpc_id: environment.vpc_id.clone(),
+                health_check_path: t.health_check_path.clone(),
+                health_check_enabled: t.health_check_enabled,
+            })
+            .collect::<Vec<TargetGroupPlan>>();
+
+        let https = input.https.unwrap_or(false);
+        let certificate = https.then(|| CertificatePlan {
+            domain_name: fqdn.clone(),
+            hosted_zone_id: environment.hosted_zone_id.clone(),
+        });
+        let listeners = match shared {
+            Some(_) => vec![],
+            None => listeners(&targets, &target_groups, https),
+        };
+
+        let load_balancer_security_group = shared.is_none().then(|| SecurityGroupPlan {
+            name: names.load_balancer_security_group(),
+            description: format!("Load balancer of deployment {}", input.deployment_slug),
+            vpc_id: environment.vpc_id.clone(),
+            ingress: listeners
+                .iter()
+                .flat_map(|l| {
+                    ingress_cidrs.iter().map(|cidr| IngressRulePlan {
+                        protocol: "TCP".to_string(),
+                        from_port: l.port,
+                        to_port: l.port,
+                        source: IngressSource::Cidr(cidr.clone()),
+                    })
+                })
+                .collect(),
+        });
+        let load_balancer_source = match shared {
+            Some(shared) => IngressSource::SecurityGroupId(shared.security_group_id.clone()),
+            None => IngressSource::SecurityGroup(names.load_balancer_security_group()),
+        };
+        let instance_security_group = SecurityGroupPlan {
+            name: names.instance_security_group(),
+            description: format!("Instances of deployment {}", input.deployment_slug),
+            vpc_id: environment.vpc_id.clone(),
+            ingress: target_groups
+                .iter()
+                .map(|tg| IngressRulePlan {
+                    protocol: "TCP".to_string(),
+                    from_port: tg.port,
+                    to_port: tg.port,
+                    source: load_balancer_source.clone(),
+                })
+                .collect(),
+        };
+
+        Ok(Self {
+            environment: environment_name.to_string(),
+            secrets,
+            certificate,
+            load_balancer_security_group,
+            instance_security_group,
+            launch_template: LaunchTemplatePlan {
+                name: names.launch_template(),
+                instance_type: input.instance_type.clone(),
+                image_id: environment.image_id.clone(),
+                device_name: "/dev/sda1".to_string(),
+                volume_size: 80,
+                template_id: input.template_id.clone(),
+                flake_url: input.flake_url.clone(),
+                files: planned_files,
+                instance_profile: environment.instance_profile.clone(),
+                security_group: names.instance_security_group(),
+                tags,
+            },
+            auto_scaling_group: AutoScalingGroupPlan {
+                name: names.auto_scaling_group(),
+                launch_template_name: names.launch_template(),
+                min_size: input.min_size.unwrap_or(1),
+                max_size: input.max_size.unwrap_or(1),
+                subnets: environment.instance_subnets.clone(),
+            },
+            load_balancer: shared.is_none().then(|| LoadBalancerPlan {
+                name: names.load_balancer(),
+                scheme,
+                subnets: load_balancer_subnets.to_vec(),
+            }),
+            shared_load_balancer: shared.map(|shared| SharedLoadBalancerPlan {
+                arn: shared.arn.clone(),
+                listener_arn: shared.listener_arn.clone(),
+                rule: ListenerRulePlan {
+                    host_header: Some(fqdn.clone()),
+                    path_pattern: targets[0].path_pattern.clone(),
+                    target_group: target_groups[0].name.clone(),
+                },
+            }),
+            target_group_attachment: TargetGroupAttachmentPlan {
+                auto_scaling_group: names.auto_scaling_group(),
+                target_groups: target_groups.iter().map(|tg| tg.name.clone()).collect(),
+            },
+            target_groups,
+            listeners,
+            record_set: RecordSetPlan {
+                hosted_zone_id: hosted_zone_id.to_string(),
+                action: "UPSERT".to_string(),
+                name: fqdn,
+                record_types: vec!["A".to_string()],
+            },
+            wait_for_healthy: input.wait_for_healthy.unwrap_or(false),
+        })
+    }
+}
+
+/// The environment's shared load balancer, if the request asks for it and
+/// its scheme is the one requested
+fn shared_load_balancer<'a>(
+    input: &DeployAWSInput,
+    environment: &'a Environment,
+) -> Result<Option<&'a SharedLoadBalancerConfig>, error::Error> {
+    if !input.shared_load_balancer.unwrap_or(false) {
+        return Ok(None);
+    }
+    let invalid = |field: &str, code: &str, message: String| {
+        error::Error::invalid_fields(vec![error::FieldError {
+            field: field.to_string(),
+            code: code.to_string(),
+            message,
+        }])
+    };
+    let shared = environment.shared_load_balancer.as_ref().ok_or_else(|| {
+        invalid(
+            "shared_load_balancer",
+            "SharedLoadBalancerNotConfigured",
+            "the environment has no shared_load_balancer".to_string(),
+        )
+    })?;
+    match input.scheme {
+        Some(scheme) if scheme != shared.scheme => Err(invalid(
+            "scheme",
+            "SharedLoadBalancerSchemeMismatch",
+            format!("the shared load balancer is {}", shared.scheme.as_str()),
+        )),
+        _ => Ok(Some(shared)),
+    }
+}
+
+/// A listener per target group, on the target's port, except for targets
+/// routed by path or host, which share one on port 80 through rules; over
+/// HTTPS that listener is on 443, serves the first target unless it is routed,
+/// and HTTP is redirected to it
+fn listeners(
+    targets: &[Target],
+    target_groups: &[TargetGroupPlan],
+    https: bool,
+) -> Vec<ListenerPlan> {
+    let protocol = if https { "HTTPS" } else { "HTTP" };
+    let mut default_action = None;
+    let mut rules = vec![];
+    let mut listeners = vec![];
+    for (i, (target, tg)) in targets.iter().zip(target_groups).enumerate() {
+        if target.routed() {
+            rules.push(ListenerRulePlan {
+                host_header: target.host_header.clone(),
+                path_pattern: target.path_pattern.clone(),
+                target_group: tg.name.clone(),
+            });
+        } else if https && i == 0 {
+            default_action = Some(ListenerAction::Forward(tg.name.clone()));
+        } else {
+            listeners.push(ListenerPlan {
+                protocol: protocol.to_string(),
+                port: tg.port,
+                action: ListenerAction::Forward(tg.name.clone()),
+                rules: vec![],
+            });
+        }
+    }
+    if default_action.is_some() || !rules.is_empty() {
+        listeners.insert(
+            0,
+            ListenerPlan {
+                protocol: protocol.to_string(),
+                port: if https { HTTPS_PORT } else { HTTP_PORT },
+                action: default_action.unwrap_or(ListenerAction::NotFound),
+                rules,
+            },
+        );
+    }
+    if https {
+        listeners.push(ListenerPlan {
+            protocol: "HTTP".to_string(),
+            port: HTTP_PORT,
+            action: ListenerAction::RedirectToHttps(HTTPS_PORT),
+            rules: vec![],
+        });
+    }
+    listeners
+}
@@ -0,0 +1,694 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::cloud::{CloudProvider, LoadBalancer};
+use crate::config::Environment;
+use crate::deployment::{AliasTarget, DeploymentPhase, DeploymentResources, RecordSet};
+use crate::error;
+use crate::files;
+use crate::plan::{
+    CertificatePlan, IngressSource, ListenerAction, Plan, SecurityGroupPlan, SharedLoadBalancerPlan,
+};
+use crate::retry::Retrier;
+use crate::saga::{Compensation, Saga};
+use crate::secrets;
+use crate::store::DeploymentStore;
+use crate::waiter::{Check, WaitError, Waiter};
+use crate::File;
+
+/// Seconds resolvers may cache a certificate validation record
+const VALIDATION_RECORD_TTL: i64 = 300;
+
+/// Listener rule priorities are unique per listener, from 1 to 50000
+const MAX_RULE_PRIORITY: i64 = 50_000;
+
+/// How often to pick another priority when a deployment joining the shared
+/// load balancer at the same time takes ours
+const RULE_PRIORITY_ATTEMPTS: u32 = 5;
+
+/// The error for a resource that did not reach the state provisioning waited
+/// for, with the state it was last seen in
+fn not_ready(err: &str, problem: String, e: WaitError) -> error::Error {
+    match e {
+        WaitError::Cloud(e) => e.during(err),
+        e => error::Error::new(err, Some(&format!("{}: {}", problem, e.describe())), 504),
+    }
+}
+
+/// Create every resource in `plan`, recording each step on the deployment
+/// and registering how to undo it with `saga`; `files` are the planned files
+/// with their content, which is only encrypted into the launch template here
+///
+/// Calls that fail transiently are retried, and waits are bounded, as
+/// `environment` configures.
+pub async fn execute(
+    store: &DeploymentStore,
+    cloud: &dyn CloudProvider,
+    environment: &Environment,
+    id: &str,
+    plan: &Plan,
+    files: &[File],
+    saga: &mut Saga,
+) -> Result<(), error::Error> {
+    let retrier = Retrier::new(&environment.retry, store, id);
+
+    // write the secrets before anything that could boot an instance
+    let deployment_secrets = secrets::deployment_secrets()?;
+    retrier
+        .call("put_secrets", || {
+            cloud
+                .secrets()
+                .put(&plan.secrets.reference, &deployment_secrets)
+        })
+        .await
+        .map_err(|e| e.during("SecretStoreFailed"))?;
+    saga.register(Compensation::DeleteSecrets(plan.secrets.reference.clone()));
+    store
+        .update(id, |r| {
+            r.phase = DeploymentPhase::SecretsStored;
+            r.resources.secrets_reference = Some(plan.secrets.reference.clone());
+        })
+        .await?;
+
+    // validation takes a while, so start it before everything else
+    let certificate_arn = match &plan.certificate {
+        Some(certificate) => Some(
+            request_certificate(store, cloud, environment, &retrier, id, certificate, saga).await?,
+        ),
+        None => None,
+    };
+
+    // the instances' group only admits the load balancer's, and has to exist
+    // before the launch template refers to it
+    let lb_sg_id = match &plan.load_balancer_security_group {
+        Some(lb_sg) => Some(
+            create_security_group(store, cloud, &retrier, id, lb_sg, &[], saga, |r, sg| {
+                r.load_balancer_security_group_id = Some(sg)
+            })
+            .await?,
+        ),
+        None => None,
+    };
+    let groups = plan
+        .load_balancer_security_group
+        .iter()
+        .zip(&lb_sg_id)
+        .map(|(sg, id)| (sg.name.as_str(), id.as_str()))
+        .collect::<Vec<(&str, &str)>>();
+    let instance_sg_id = create_security_group(
+        store,
+        cloud,
+        &retrier,
+        id,
+        &plan.instance_security_group,
+        &groups,
+        saga,
+        |r, sg| r.instance_security_group_id = Some(sg),
+    )
+    .await?;
+    store
+        .update(id, |r| r.phase = DeploymentPhase::SecurityGroupsCreated)
+        .await?;
+
+    let user_data = files::user_data(files)?;
+    let launch_template_id = retrier
+        .call("create_launch_template", || {
+            cloud.create_launch_template(
+                &plan.launch_template,
+                &instance_sg_id,
+                user_data.as_deref(),
+            )
+        })
+        .await
+        .map_err(|e| e.during("LaunchTemplateCreationFailed"))?;
+    saga.register(Compensation::DeleteLaunchTemplate(
+        launch_template_id.clone(),
+    ));
+    store
+        .update(id, |r| {
+            r.phase = DeploymentPhase::LaunchTemplateCreated;
+            r.resources.launch_template_id = Some(launch_template_id);
+        })
+        .await?;
+
+    // create auto scaling group
+    let asg = &plan.auto_scaling_group;
+    retrier
+        .call_dependent("create_auto_scaling_group", || {
+            cloud.create_auto_scaling_group(asg)
+        })
+        .await
+        .map_err(|e| e.during("AutoScalingGroupCreationFailed"))?;
+    saga.register(Compensation::DeleteAutoScalingGroup(asg.name.clone()));
+    store
+        .update(id, |r| {
+            r.phase = DeploymentPhase::AutoScalingGroupCreated;
+            r.resources.auto_scaling_group_name = Some(asg.name.clone());
+        })
+        .await?;
+
+    // target group name -> arn, in the order they were created
+    let mut target_group_arns: Vec<(String, String)> = vec![];
+
+    for tg in &plan.target_groups {
+        let arn = retrier
+            .call("create_target_group", || cloud.create_target_group(tg))
+            .await
+            .map_err(|e| e.during("TargetGroupCreationFailed"))?;
+        saga.register(Compensation::DeleteTargetGroup(arn.clone()));
+        store
+            .update(id, |r| r.resources.target_group_arns.push(arn.clone()))
+            .await?;
+        target_group_arns.push((tg.name.clone(), arn));
+    }
+
+    store
+        .update(id, |r| r.phase = DeploymentPhase::TargetGroupsCreated)
+        .await?;
+
+    let target_group_arn = |name: &str| -> Result<String, error::Error> {
+        target_group_arns
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, arn)| arn.clone())
+            .ok_or_else(|| {
+                error::Error::new(
+                    "TargetGroupNotFound",
+                    Some(&format!("target group {} was not created", name)),
+                    500,
+                )
+            })
+    };
+
+    let load_balancer = match &plan.shared_load_balancer {
+        Some(shared) => {
+            let target_group_arn = target_group_arn(&shared.rule.target_group)?;
+            join_shared_load_balancer(store, cloud, &retrier, id, shared, &target_group_arn, saga)
+                .await?
+        }
+        None => {
+            let (Some(load_balancer_plan), Some(lb_sg_id)) = (&plan.load_balancer, &lb_sg_id)
+            else {
+                return Err(error::Error::new(
+                    "LoadBalancerNotPlanned",
+                    Some("the plan has neither a load balancer nor a shared one"),
+                    500,
+                ));
+            };
+            // create load balancer
+            let load_balancer = retrier
+                .call_dependent("create_load_balancer", || {
+                    cloud.create_load_balancer(load_balancer_plan, lb_sg_id)
+                })
+                .await
+                .map_err(|e| e.during("LoadBalancerCreationFailed"))?;
+            saga.register(Compensation::DeleteLoadBalancer(load_balancer.arn.clone()));
+            store
+                .update(id, |r| {
+                    r.phase = DeploymentPhase::LoadBalancerCreated;
+                    r.resources.load_balancer_arn = Some(load_balancer.arn.clone());
+                    r.resources.load_balancer_dns = Some(load_balancer.dns_name.clone());
+                })
+                .await?;
+
+            // listeners can only be added once the load balancer is active
+            Waiter::new(&environment.waits.load_balancer)
+                .until(|| async {
+                    Ok(match cloud.load_balancer_state(&load_balancer.arn).await? {
+                        Some(state) if state.code == "active" => Check::Done(()),
+                        Some(state) if state.code == "failed" => Check::Failed(state.to_string()),
+                        Some(state) => Check::Waiting(state.to_string()),
+                        None => Check::Failed("deleted".to_string()),
+                    })
+                })
+                .await
+                .map_err(|e| {
+                    not_ready(
+                        "LoadBalancerNotActive",
+                        format!("load balancer {} is not active", load_balancer.arn),
+                        e,
+                    )
+                })?;
+            store
+                .update(id, |r| r.phase = DeploymentPhase::LoadBalancerActive)
+                .await?;
+
+            // HTTPS listeners need the certificate to be issued
+            if let Some(arn) = &certificate_arn {
+                Waiter::new(&environment.waits.certificate)
+                    .until(|| async {
+                        let certificate = cloud.certificate(arn).await?;
+                        Ok(if certificate.issued() {
+                            Check::Done(())
+                        } else if certificate.failed() {
+                            Check::Failed(certificate.status)
+                        } else {
+                            Check::Waiting(certificate.status)
+                        })
+                    })
+                    .await
+                    .map_err(|e| {
+                        not_ready(
+                            "CertificateNotIssued",
+                            format!("certificate {} was not issued", arn),
+                            e,
+                        )
+                    })?;
+                store
+                    .update(id, |r| r.phase = DeploymentPhase::CertificateIssued)
+                    .await?;
+            }
+
+            // create listener
+            for listener in &plan.listeners {
+                let forward_to = match &listener.action {
+                    ListenerAction::Forward(name) => Some(target_group_arn(name)?),
+                    ListenerAction::RedirectToHttps(_) | ListenerAction::NotFound => None,
+                };
+                let certificate_arn = match listener.protocol.as_str() {
+                    "HTTPS" => certificate_arn.as_deref(),
+                    _ => None,
+                };
+                let arn = retrier
+                    .call_dependent("create_listener", || {
+                        cloud.create_listener(
+                            &load_balancer.arn,
+                            listener,
+                            forward_to.as_deref(),
+                            certificate_arn,
+                        )
+                    })
+                    .await
+                    .map_err(|e| e.during("ListenerCreationFailed"))?;
+                saga.register(Compensation::DeleteListener(arn.clone()));
+                store
+                    .update(id, |r| r.resources.listener_arns.push(arn.clone()))
+                    .await?;
+
+                // the listener is the deployment's own, so rules take the
+                // priorities in order
+                for (priority, rule) in (1..).zip(&listener.rules) {
+                    let rule_target = target_group_arn(&rule.target_group)?;
+                    let rule_arn = retrier
+                        .call_dependent("create_listener_rule", || {
+                            cloud.create_listener_rule(&arn, rule, priority, &rule_target)
+                        })
+                        .await
+                        .map_err(|e| e.during("ListenerRuleCreationFailed"))?;
+                    saga.register(Compensation::DeleteListenerRule(rule_arn.clone()));
+                    store
+                        .update(id, |r| r.resources.listener_rule_arns.push(rule_arn))
+                        .await?;
+                }
+            }
+
+            store
+                .update(id, |r| r.phase = DeploymentPhase::ListenersCreated)
+                .await?;
+            load_balancer
+        }
+    };
+
+    // attach target group to auto scaling group
+    let attachment = &plan.target_group_attachment;
+    let attached_arns = attachment
+        .target_groups
+        .iter()
+        .map(|name| target_group_arn(name))
+        .collect::<Result<Vec<String>, error::Error>>()?;
+    retrier
+        .call_dependent("attach_target_groups", || {
+            cloud.attach_target_groups(&attachment.auto_scaling_group, &attached_arns)
+        })
+        .await
+        .map_err(|e| e.during("TargetGroupAttachFailed"))?;
+    saga.register(Compensation::DetachTargetGroups {
+        auto_scaling_group_name: attachment.auto_scaling_group.clone(),
+        target_group_arns: attached_arns,
+    });
+    store
+        .update(id, |r| r.phase = DeploymentPhase::TargetGroupsAttached)
+        .await?;
+
+    let asg_name = &plan.auto_scaling_group.name;
+    let min_size = plan.auto_scaling_group.min_size;
+    Waiter::new(&environment.waits.instances)
+        .until(|| async {
+            let instances = cloud.auto_scaling_group_instances(asg_name).await?;
+            let in_service = instances.iter().filter(|i| i.in_service()).count();
+            Ok(if in_service as i64 >= min_size {
+                Check::Done(())
+            } else {
+                Check::Waiting(format!(
+                    "{} of {} instances in service: {}",
+                    in_service,
+                    min_size,
+                    instances
+                        .iter()
+                        .map(|i| format!("{} {}/{}", i.id, i.lifecycle_state, i.health_status))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ))
+            })
+        })
+        .await
+        .map_err(|e| {
+            not_ready(
+                "InstancesNotInService",
+                format!(
+                    "auto scaling group {} has too few instances in service",
+                    asg_name
+                ),
+                e,
+            )
+        })?;
+    store
+        .update(id, |r| r.phase = DeploymentPhase::InstancesInService)
+        .await?;
+
+    // point the deployment's name at the load balancer
+    let rs = &plan.record_set;
+    let mut change_ids = vec![];
+    for record_type in &rs.record_types {
+        let mut record_set = RecordSet {
+            hosted_zone_id: rs.hosted_zone_id.clone(),
+            name: rs.name.clone(),
+            record_type: record_type.clone(),
+            ttl: None,
+            value: None,
+            alias_target: Some(AliasTarget {
+                hosted_zone_id: load_balancer.canonical_hosted_zone_id.clone(),
+                dns_name: load_balancer.dns_name.clone(),
+            }),
+            change_id: None,
+        };
+        let change_id = retrier
+            .call("change_record_set", || {
+                cloud.change_record_set(&rs.action, &record_set)
+            })
+            .await
+            .map_err(|e| e.during("RecordSetCreationFailed"))?;
+        record_set.change_id = Some(change_id.clone());
+        change_ids.push(change_id);
+        saga.register(Compensation::DeleteRecordSet(record_set.clone()));
+        store
+            .update(id, |r| r.resources.record_sets.push(record_set))
+            .await?;
+    }
+
+    if plan.wait_for_healthy {
+        let arns = target_group_arns
+            .into_iter()
+            .map(|(_, arn)| arn)
+            .collect::<Vec<String>>();
+        wait_for_healthy(store, cloud, environment, id, plan, &arns, &change_ids).await?;
+    }
+
+    Ok(())
+}
+
+/// Route the deployment's name on the shared load balancer to its target
+/// group with a rule at the lowest free priority, returning the load balancer
+async fn join_shared_load_balancer(
+    store: &DeploymentStore,
+    cloud: &dyn CloudProvider,
+    retrier: &Retrier<'_>,
+    id: &str,
+    plan: &SharedLoadBalancerPlan,
+    target_group_arn: &str,
+    saga: &mut Saga,
+) -> Result<LoadBalancer, error::Error> {
+    let load_balancer = retrier
+        .call("load_balancer", || cloud.load_balancer(&plan.arn))
+        .await
+        .map_err(|e| e.during("SharedLoadBalancerLookupFailed"))?
+        .ok_or_else(|| {
+            error::Error::new(
+                "SharedLoadBalancerNotFound",
+                Some(&format!("shared load balancer {} does not exist", plan.arn)),
+                500,
+            )
+        })?;
+
+    let mut attempt = 1;
+    let arn = loop {
+        let taken = retrier
+            .call("listener_rule_priorities", || {
+                cloud.listener_rule_priorities(&plan.listener_arn)
+            })
+            .await
+            .map_err(|e| e.during("ListenerRuleCreationFailed"))?
+            .into_iter()
+            .collect::<BTreeSet<i64>>();
+        let priority = (1..=MAX_RULE_PRIORITY)
+            .find(|p| !taken.contains(p))
+            .ok_or_else(|| {
+                error::Error::new(
+                    "ListenerRulePrioritiesExhausted",
+                    Some(&format!(
+                        "listener {} has no free rule priority",
+                        plan.listener_arn
+                    )),
+                    409,
+                )
+            })?;
+        let created = retrier
+            .call_dependent("create_listener_rule", || {
+                cloud.create_listener_rule(
+                    &plan.listener_arn,
+                    &plan.rule,
+                    priority,
+                    target_group_arn,
+                )
+            })
+            .await;
+        match created {
+            Ok(arn) => break arn,
+            // another deployment took the priority since we looked
+            Err(e)
+                if e.code.as_deref() == Some("PriorityInUse")
+                    && attempt < RULE_PRIORITY_ATTEMPTS =>
+            {
+                attempt += 1;
+            }
+            Err(e) => return Err(e.during("ListenerRuleCreationFailed")),
+        }
+    };
+    saga.register(Compensation::DeleteListenerRule(arn.clone()));
+    store
+        .update(id, |r| {
+            r.phase = DeploymentPhase::ListenerRuleCreated;
+            r.resources.listener_rule_arns.push(arn);
+        })
+        .await?;
+    Ok(load_balancer)
+}
+
+/// Create a security group and authorize its ingress, returning its id
+///
+/// Rules may only admit groups in `groups`, given as (name, id) pairs, or
+/// existing groups by id.
+#[allow(clippy::too_many_arguments)]
+async fn create_security_group(
+    store: &DeploymentStore,
+    cloud: &dyn CloudProvider,
+    retrier: &Retrier<'_>,
+    id: &str,
+    plan: &SecurityGroupPlan,
+    groups: &[(&str, &str)],
+    saga: &mut Saga,
+    record: impl FnOnce(&mut DeploymentResources, String),
+) -> Result<String, error::Error> {
+    let group_id = retrier
+        .call("create_security_group", || {
+            cloud.create_security_group(plan)
+        })
+        .await
+        .map_err(|e| e.during("SecurityGroupCreationFailed"))?;
+    saga.register(Compensation::DeleteSecurityGroup(group_id.clone()));
+    store
+        .update(id, |r| record(&mut r.resources, group_id.clone()))
+        .await?;
+
+    for rule in &plan.ingress {
+        let source_group_id = match &rule.source {
+            IngressSource::Cidr(_) => None,
+            IngressSource::SecurityGroupId(group_id) => Some(group_id.as_str()),
+            IngressSource::SecurityGroup(name) => Some(
+                groups
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, id)| *id)
+                    .ok_or_else(|| {
+                        error::Error::new(
+                            "SecurityGroupNotFound",
+                            Some(&format!("security group {} was not created", name)),
+                            500,
+                        )
+                    })?,
+            ),
+        };
+        retrier
+            .call_dependent("authorize_ingress", || {
+                cloud.authorize_ingress(&group_id, rule, source_group_id)
+            })
+            .await
+            .map_err(|e| e.during("SecurityGroupIngressRulesAdditionFailed"))?;
+    }
+    Ok(group_id)
+}
+
+/// Request the certificate and create the DNS records that validate it,
+/// returning its ARN
+async fn request_certificate(
+    store: &DeploymentStore,
+    cloud: &dyn CloudProvider,
+    environment: &Environment,
+    retrier: &Retrier<'_>,
+    id: &str,
+    plan: &CertificatePlan,
+    saga: &mut Saga,
+) -> Result<String, error::Error> {
+    let arn = retrier
+        .call("request_certificate", || cloud.request_certificate(plan))
+        .await
+        .map_err(|e| e.during("CertificateRequestFailed"))?;
+    saga.register(Compensation::DeleteCertificate(arn.clone()));
+    store
+        .update(id, |r| r.resources.certificate_arn = Some(arn.clone()))
+        .await?;
+
+    // the validation records are generated shortly after the request
+    let validation_records = Waiter::new(&environment.waits.certificate)
+        .until(|| async {
+            let certificate = cloud.certificate(&arn).await?;
+            Ok(if certificate.failed() {
+                Check::Failed(certificate.status)
+            } else if certificate.validation_records.is_empty() {
+                Check::Waiting(format!("{} without validation records", certificate.status))
+            } else {
+                Check::Done(certificate.validation_records)
+            })
+        })
+        .await
+        .map_err(|e| {
+            not_ready(
+                "CertificateNotIssued",
+                format!("certificate {} has no validation records", arn),
+                e,
+            )
+        })?;
+
+    for record in validation_records {
+        let mut record_set = RecordSet {
+            hosted_zone_id: plan.hosted_zone_id.clone(),
+            name: record.name,
+            record_type: record.record_type,
+            ttl: Some(VALIDATION_RECORD_TTL),
+            value: Some(record.value),
+            alias_target: None,
+            change_id: None,
+        };
+        // every certificate for the domain validates with the same record, and
+        // ACM needs it to renew them, so it is never deleted with a deployment
+        let change_id = retrier
+            .call("change_record_set", || {
+                cloud.change_record_set("UPSERT", &record_set)
+            })
+            .await
+            .map_err(|e| e.during("CertificateValidationRecordCreationFailed"))?;
+        record_set.change_id = Some(change_id);
+        store
+            .update(id, |r| r.resources.validation_record_sets.push(record_set))
+            .await?;
+    }
+
+    store
+        .update(id, |r| r.phase = DeploymentPhase::CertificateRequested)
+        .await?;
+    Ok(arn)
+}
+
+/// Wait until at least `min_size` targets are healthy in every target group,
+/// recording their health on the deployment as it goes, and then until the
+/// record set changes are in sync
+async fn wait_for_healthy(
+    store: &DeploymentStore,
+    cloud: &dyn CloudProvider,
+    environment: &Environment,
+    id: &str,
+    plan: &Plan,
+    target_group_arns: &[String],
+    change_ids: &[String],
+) -> Result<(), error::Error> {
+    let min_size = plan.auto_scaling_group.min_size;
+    Waiter::new(&environment.waits.targets)
+        .until(|| async {
+            let mut health = BTreeMap::new();
+            for arn in target_group_arns {
+                health.insert(arn.clone(), cloud.target_health(arn).await?);
+            }
+            let recorded = store.update(id, |r| r.target_health = health.clone()).await;
+            if let Err(e) = recorded {
+                println!("Deployment {}: failed to record target health: {}", id, e);
+            }
+            let unhealthy = health
+                .iter()
+                .filter(|(_, targets)| {
+                    (targets.iter().filter(|t| t.healthy()).count() as i64) < min_size
+                })
+                .map(|(arn, targets)| {
+                    let states = targets
+                        .iter()
+                        .map(|t| match &t.reason {
+                            Some(reason) => format!("{} {} ({})", t.id, t.state, reason),
+                            None => format!("{} {}", t.id, t.state),
+                        })
+                        .collect::<Vec<String>>();
+                    format!("{}: [{}]", arn, states.join(", "))
+                })
+                .collect::<Vec<String>>();
+            Ok(if unhealthy.is_empty() {
+                Check::Done(())
+            } else {
+                Check::Waiting(format!(
+                    "fewer than {} healthy targets in {}",
+                    min_size,
+                    unhealthy.join("; ")
+                ))
+            })
+        })
+        .await
+        .map_err(|e| {
+            not_ready(
+                "TargetsNotHealthy",
+                "targets did not pass their health checks".to_string(),
+                e,
+            )
+        })?;
+    store
+        .update(id, |r| r.phase = DeploymentPhase::TargetsHealthy)
+        .await?;
+
+    for change_id in change_ids {
+        Waiter::new(&environment.waits.record_set)
+            .until(|| async {
+                let status = cloud.record_set_change_status(change_id).await?;
+                Ok(match status.as_str() {
+                    "INSYNC" => Check::Done(()),
+                    _ => Check::Waiting(status),
+                })
+            })
+            .await
+            .map_err(|e| {
+                not_ready(
+                    "RecordSetNotInSync",
+                    format!("record set change {} is not in sync", change_id),
+                    e,
+                )
+            })?;
+    }
+    store
+        .update(id, |r| r.phase = DeploymentPhase::RecordSetInSync)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::config::RetryConfig;
+use crate::error::{CloudError, CloudErrorKind};
+use crate::store::DeploymentStore;
+
+/// Whether retrying the call that failed with `e` may succeed
+///
+/// `dependent` calls reference a resource created just before them, which
+/// AWS may briefly report as not found while it is eventually consistent.
+fn retryable(e: &CloudError, dependent: bool) -> bool {
+    match e.kind {
+        CloudErrorKind::Throttled | CloudErrorKind::Unavailable => true,
+        CloudErrorKind::NotFound => dependent,
+        _ => false,
+    }
+}
+
+/// How long to wait before retry number `retry` (starting at 1): exponential
+/// backoff capped at `max_delay_ms`, of which a random half is jitter
+fn delay(config: &RetryConfig, retry: u32) -> Duration {
+    let exponential = config
+        .base_delay_ms
+        .saturating_mul(1 << retry.saturating_sub(1).min(20));
+    let cap = exponential.min(config.max_delay_ms);
+    let jitter = rand::thread_rng().gen_range(0..=cap / 2);
+    Duration::from_millis(cap - cap / 2 + jitter)
+}
+
+/// Retries the cloud calls of one deployment, counting the retries on its
+/// record
+pub struct Retrier<'a> {
+    config: &'a RetryConfig,
+    store: &'a DeploymentStore,
+    id: &'a str,
+}
+
+impl<'a> Retrier<'a> {
+    pub fn new(config: &'a RetryConfig, store: &'a DeploymentStore, id: &'a str) -> Self {
+        Self { config, store, id }
+    }
+
+    /// Call `f` until it succeeds, fails with an error retrying can't fix, or
+    /// runs out of attempts
+    pub async fn call<T, F, Fut>(&self, operation: &str, f: F) -> Result<T, CloudError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, CloudError>>,
+    {
+        self.run(operation, false, f).await
+    }
+
+    /// Like [`Retrier::call`], but also retries "not found" errors, for calls
+    /// that use a resource created just before them
+    pub async fn call_dependent<T, F, Fut>(&self, operation: &str, f: F) -> Result<T, CloudError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, CloudError>>,
+    {
+        self.run(operation, true, f).await
+    }
+
+    async fn run<T, F, Fut>(&self, operation: &str, dependent: bool, f: F) -> Result<T, CloudError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, CloudError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if attempt < self.config.max_attempts && retryable(&e, dependent) => {
+                    println!(
+                        "Deployment {}: {} failed (attempt {} of {}), retrying: {}",
+                        self.id, operation, attempt, self.config.max_attempts, e
+                    );
+                    let recorded = self
+                        .store
+                        .update(self.id, |r| {
+                            *r.retries.entry(operation.to_string()).or_default() += 1;
+                        })
+                        .await;
+                    if let Err(e) = recorded {
+                        println!("Deployment {}: failed to record retry: {}", self.id, e);
+                    }
+                    tokio::time::sleep(delay(self.config, attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use crate::cloud::CloudProvider;
+use crate::config::WaitsConfig;
+use crate::deployment::{DeploymentResources, RecordSet};
+use crate::teardown::{self, ResourceTeardown};
+
+/// Undoes a single completed provisioning step
+#[derive(Clone, Debug)]
+pub enum Compensation {
+    DeleteSecrets(String),
+    DeleteLaunchTemplate(String),
+    DeleteAutoScalingGroup(String),
+    DeleteTargetGroup(String),
+    DeleteSecurityGroup(String),
+    DeleteLoadBalancer(String),
+    DeleteListener(String),
+    DeleteListenerRule(String),
+    DetachTargetGroups {
+        auto_scaling_group_name: String,
+        target_group_arns: Vec<String>,
+    },
+    DeleteRecordSet(RecordSet),
+    DeleteCertificate(String),
+}
+
+/// Resources a rollback could not delete, which the resources created before
+/// them may still be in use by
+#[derive(Default)]
+struct Remaining {
+    auto_scaling_group: bool,
+    load_balancer: bool,
+    listeners: bool,
+    security_group: bool,
+}
+
+impl Compensation {
+    /// The kind and id of the resource this compensation deletes
+    fn resource(&self) -> (&'static str, String) {
+        match self {
+            Compensation::DeleteSecrets(reference) => ("Secrets", reference.clone()),
+            Compensation::DeleteLaunchTemplate(id) => ("LaunchTemplate", id.clone()),
+            Compensation::DeleteAutoScalingGroup(name) => ("AutoScalingGroup", name.clone()),
+            Compensation::DeleteTargetGroup(arn) => ("TargetGroup", arn.clone()),
+            Compensation::DeleteSecurityGroup(id) => ("SecurityGroup", id.clone()),
+            Compensation::DeleteLoadBalancer(arn) => ("LoadBalancer", arn.clone()),
+            Compensation::DeleteListener(arn) => ("Listener", arn.clone()),
+            Compensation::DeleteListenerRule(arn) => ("ListenerRule", arn.clone()),
+            Compensation::DetachTargetGroups {
+                auto_scaling_group_name,
+                ..
+            } => ("TargetGroupAttachment", auto_scaling_group_name.clone()),
+            Compensation::DeleteRecordSet(record_set) => (
+                "RecordSet",
+                format!("{} {}", record_set.name, record_set.record_type),
+            ),
+            Compensation::DeleteCertificate(arn) => ("Certificate", arn.clone()),
+        }
+    }
+
+    async fn run(&self, cloud: &dyn CloudProvider, waits: &WaitsConfig) -> ResourceTeardown {
+        let result = match self {
+            Compensation::DeleteSecrets(reference) => cloud.secrets().delete(reference).await,
+            Compensation::DeleteLaunchTemplate(id) => cloud.delete_launch_template(id).await,
+            Compensation::DeleteAutoScalingGroup(name) => {
+                teardown::delete_auto_scaling_group(cloud, &waits.auto_scaling_group_deletion, name)
+                    .await
+            }
+            Compensation::DeleteTargetGroup(arn) => cloud.delete_target_group(arn).await,
+            Compensation::DeleteSecurityGroup(id) => cloud.delete_security_group(id).await,
+            Compensation::DeleteLoadBalancer(arn) => {
+                teardown::delete_load_balancer(cloud, &waits.load_balancer, arn).await
+            }
+            Compensation::DeleteListener(arn) => cloud.delete_listener(arn).await,
+            Compensation::DeleteListenerRule(arn) => cloud.delete_listener_rule(arn).await,
+            Compensation::DetachTargetGroups {
+                auto_scaling_group_name,
+                target_group_arns,
+            } => {
+                cloud
+                    .detach_target_groups(auto_scaling_group_name, target_group_arns)
+                    .await
+            }
+            Compensation::DeleteRecordSet(record_set) => cloud
+                .change_record_set("DELETE", record_set)
+                .await
+                .map(|_| ()),
+            Compensation::DeleteCertificate(arn) => cloud.delete_certificate(arn).await,
+        };
+        let (resource, id) = self.resource();
+        ResourceTeardown::new(resource, &id, result)
+    }
+
+    /// Why this compensation can't run while the `remaining` resources exist,
+    /// following the same order `teardown` deletes in
+    fn blocked_by(&self, remaining: &Remaining) -> Option<&'static str> {
+        match self {
+            Compensation::DeleteTargetGroup(_)
+                if remaining.auto_scaling_group || remaining.listeners =>
+            {
+                Some("auto scaling group, listeners or listener rules still exist")
+            }
+            Compensation::DeleteSecurityGroup(_)
+                if remaining.auto_scaling_group
+                    || remaining.load_balancer
+                    || remaining.security_group =>
+            {
+                Some("auto scaling group, load balancer or another security group still exists")
+            }
+            Compensation::DeleteCertificate(_)
+                if remaining.load_balancer || remaining.listeners =>
+            {
+                Some("load balancer or listeners still exist")
+            }
+            // instances read their secrets at boot
+            Compensation::DeleteLaunchTemplate(_) | Compensation::DeleteSecrets(_)
+                if remaining.auto_scaling_group =>
+            {
+                Some("auto scaling group still exists")
+            }
+            _ => None,
+        }
+    }
+
+    /// Note that the resource of this compensation was left in place
+    fn remains(&self, remaining: &mut Remaining) {
+        match self {
+            Compensation::DeleteAutoScalingGroup(_) => remaining.auto_scaling_group = true,
+            Compensation::DeleteLoadBalancer(_) => remaining.load_balancer = true,
+            Compensation::DeleteListener(_) | Compensation::DeleteListenerRule(_) => {
+                remaining.listeners = true
+            }
+            Compensation::DeleteSecurityGroup(_) => remaining.security_group = true,
+            _ => {}
+        }
+    }
+
+    /// Remove the resource this compensation deleted from `resources`
+    fn forget(&self, resources: &mut DeploymentResources) {
+        match self {
+            Compensation::DeleteSecrets(_) => resources.secrets_reference = None,
+            Compensation::DeleteLaunchTemplate(_) => resources.launch_template_id = None,
+            Compensation::DeleteAutoScalingGroup(_) => resources.auto_scaling_group_name = None,
+            Compensation::DeleteTargetGroup(arn) => {
+                resources.target_group_arns.retain(|a| a != arn)
+            }
+            Compensation::DeleteSecurityGroup(id) => {
+                for group in [
+                    &mut resources.load_balancer_security_group_id,
+                    &mut resources.instance_security_group_id,
+                ] {
+                    if group.as_ref() == Some(id) {
+                        *group = None;
+                    }
+                }
+            }
+            Compensation::DeleteLoadBalancer(_) => {
+                resources.load_balancer_arn = None;
+                resources.load_balancer_dns = None;
+            }
+            Compensation::DeleteListener(arn) => resources.listener_arns.retain(|a| a != arn),
+            Compensation::DeleteListenerRule(arn) => {
+                resources.listener_rule_arns.retain(|a| a != arn)
+            }
+            Compensation::DetachTargetGroups { .. } => {}
+            Compensation::DeleteRecordSet(record_set) => {
+                resources.record_sets.retain(|r| !r.same_record(record_set))
+            }
+            Compensation::DeleteCertificate(_) => resources.certificate_arn = None,
+        }
+    }
+}
+
+/// Collects an undo action for every completed provisioning step so a failed
+/// deployment can be rolled back instead of leaving orphaned resources behind
+#[derive(Default)]
+pub struct Saga {
+    compensations: Vec<Compensation>,
+}
+
+impl Saga {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, compensation: Compensation) {
+        self.compensations.push(compensation);
+    }
+
+    /// Run every registered compensation, most recent first
+    ///
+    /// Resources that were rolled back are removed from `resources`; the ones
+    /// that could not be are left on it so they can be cleaned up later, and
+    /// so are the ones still in use by them, which are reported as `Skipped`.
+    pub async fn unwind(
+        self,
+        cloud: &dyn CloudProvider,
+        waits: &WaitsConfig,
+        resources: &mut DeploymentResources,
+    ) -> Vec<ResourceTeardown> {
+        let mut results = vec![];
+        let mut remaining = Remaining::default();
+        for compensation in self.compensations.into_iter().rev() {
+            let result = match compensation.blocked_by(&remaining) {
+                Some(reason) => {
+                    let (resource, id) = compensation.resource();
+                    ResourceTeardown::skipped(resource, &id, reason)
+                }
+                None => compensation.run(cloud, waits).await,
+            };
+            if result.succeeded() {
+                compensation.forget(resources);
+            } else {
+                compensation.remains(&mut remaining);
+            }
+            results.push(result);
+        }
+        results
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::okapi::schemars::JsonSchema;
+use tokio::sync::Mutex;
+
+use crate::aws::classify;
+use crate::config::{SecretsBackend, SecretsConfig};
+use crate::error::{self, CloudError, CloudErrorKind};
+
+/// Secrets every instance needs, by name in the secret store and the
+/// environment variable the service reads them from
+pub const DEPLOYMENT_SECRETS: [(&str, &str); 2] = [
+    ("turso_token", "TURSO_TOKEN"),
+    ("file_encryption_key", "FILE_ENCRYPTION_KEY"),
+];
+
+/// Where the secrets of a deployment are written
+///
+/// Instances are only given `backend` and `reference` (as instance tags) and
+/// read the secrets themselves through their instance profile. That profile
+/// is the environment's, shared by all of its deployments, so `reference`
+/// keeps secrets apart by name only, not by who may read them.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SecretsPlan {
+    pub backend: SecretsBackend,
+    /// The SSM parameter path, Secrets Manager secret name or local key
+    pub reference: String,
+    pub names: Vec<String>,
+}
+
+impl SecretsPlan {
+    pub fn new(config: &SecretsConfig, slug: &str) -> Self {
+        Self {
+            backend: config.backend,
+            reference: format!("{}/{}", config.prefix.trim_end_matches('/'), slug),
+            names: DEPLOYMENT_SECRETS
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Read the deployment secrets from the service's environment
+pub fn deployment_secrets() -> Result<BTreeMap<String, String>, error::Error> {
+    DEPLOYMENT_SECRETS
+        .iter()
+        .map(|(name, var)| {
+            let value = std::env::var(var).map_err(|e| {
+                error::Error::new("SecretReadFailed", Some(&format!("{}: {}", var, e)), 500)
+            })?;
+            Ok((name.to_string(), value))
+        })
+        .collect()
+}
+
+/// Somewhere deployment secrets can be kept out of the launch template
+#[async_trait]
+pub trait SecretStore: Send + Sync {
+    /// Write `secrets` under `reference`, replacing any already there
+    async fn put(
+        &self,
+        reference: &str,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<(), CloudError>;
+
+    /// Delete every secret under `reference`
+    async fn delete(&self, reference: &str) -> Result<(), CloudError>;
+}
+
+/// Build the store `config` selects
+pub fn from_config(
+    config: &SecretsConfig,
+    sdk_config: &aws_config::SdkConfig,
+) -> Box<dyn SecretStore> {
+    match config.backend {
+        SecretsBackend::Ssm => Box::new(SsmSecretStore {
+            client: aws_sdk_ssm::Client::new(sdk_config),
+        }),
+        SecretsBackend::SecretsManager => Box::new(SecretsManagerSecretStore {
+            client: aws_sdk_secretsmanager::Client::new(sdk_config),
+        }),
+        SecretsBackend::Local => {
+            Box::new(LocalSecretStore::new(config.dir.clone().map(PathBuf::from)))
+        }
+    }
+}
+
+/// One SSM Parameter Store SecureString per secret, at `<reference>/<name>`
+pub struct SsmSecretStore {
+    client: aws_sdk_ssm::Client,
+}
+
+impl SsmSecretStore {
+    async fn names(&self, reference: &str) -> Result<Vec<String>, CloudError> {
+        let mut names = vec![];
+        let mut next_token = None;
+        loop {
+            let output = self
+                .client
+                .get_parameters_by_path()
+                .path(reference)
+                .set_next_token(next_token)
+                .send()
+                .await
+                .map_err(classify)?;
+            names.extend(
+                output
+                    .parameters()
+                    .iter()
+                    .filter_map(|p| p.name().map(|n| n.to_string())),
+            );
+            next_token = output.next_token().map(|t| t.to_string());
+            if next_token.is_none() {
+                return Ok(names);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SecretStore for SsmSecretStore {
+    async fn put(
+        &self,
+        reference: &str,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<(), CloudError> {
+        for (name, value) in secrets {
+            self.client
+                .put_parameter()
+                .name(format!("{}/{}", reference, name))
+                .value(value)
+                .r#type(aws_sdk_ssm::types::ParameterType::SecureString)
+                .overwrite(true)
+                .send()
+                .await
+                .map_err(classify)?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, reference: &str) -> Result<(), CloudError> {
+        let names = self.names(reference).await?;
+        if names.is_empty() {
+            return Err(CloudError::new(
+                CloudErrorKind::NotFound,
+                format!("parameters under {} not found", reference),
+            ));
+        }
+        // DeleteParameters accepts at most 10 names per call
+        for chunk in names.chunks(10) {
+            self.client
+                .delete_parameters()
+                .set_names(Some(chunk.to_vec()))
+                .send()
+                .await
+                .map_err(classify)?;
+        }
+        Ok(())
+    }
+}
+
+/// One Secrets Manager secret named `reference`, holding the secrets as a
+/// JSON object
+pub struct SecretsManagerSecretStore {
+    client: aws_sdk_secretsmanager::Client,
+}
+
+#[async_trait]
+impl SecretStore for SecretsManagerSecretStore {
+    async fn put(
+        &self,
+        reference: &str,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<(), CloudError> {
+        let secret_string = serde_json::to_string(secrets).map_err(local)?;
+        let created = self
+            .client
+            .create_secret()
+            .name(reference)
+            .secret_string(&secret_string)
+            .send()
+            .await;
+        match created {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_service_error()
+                    .map(|e| e.is_resource_exists_exception())
+                    .unwrap_or(false) =>
+            {
+                self.client
+                    .put_secret_value()
+                    .secret_id(reference)
+                    .secret_string(secret_string)
+                    .send()
+                    .await
+                    .map(|_| ())
+                    .map_err(classify)
+            }
+            Err(e) => Err(classify(e)),
+        }
+    }
+
+    async fn delete(&self, reference: &str) -> Result<(), CloudError> {
+        self.client
+            .delete_secret()
+            .secret_id(reference)
+            .force_delete_without_recovery(true)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(classify)
+    }
+}
+
+/// Secrets kept in memory, and written to one JSON file per reference when
+/// `dir` is set; for development and tests only
+#[derive(Default)]
+pub struct LocalSecretStore {
+    dir: Option<PathBuf>,
+    secrets: Mutex<BTreeMap<String, BTreeMap<String, String>>>,
+}
+
+impl LocalSecretStore {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            secrets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn path(&self, reference: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| {
+            dir.join(format!(
+                "{}.json",
+                reference.trim_start_matches('/').replace('/', "_")
+            ))
+        })
+    }
+}
+
+fn local<E: std::error::Error>(e: E) -> CloudError {
+    CloudError::new(CloudErrorKind::Other, e.to_string())
+}
+
+#[async_trait]
+impl SecretStore for LocalSecretStore {
+    async fn put(
+        &self,
+        reference: &str,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<(), CloudError> {
+        if let Some(path) = self.path(reference) {
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await.map_err(local)?;
+            }
+            let body = serde_json::to_vec(secrets).map_err(local)?;
+            tokio::fs::write(&path, body).await.map_err(local)?;
+        }
+        self.secrets
+            .lock()
+            .await
+            .insert(reference.to_string(), secrets.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, reference: &str) -> Result<(), CloudError> {
+        let in_memory = self.secrets.lock().await.remove(reference).is_some();
+        let on_disk = match self.path(reference) {
+            Some(path) => tokio::fs::remove_file(path).await.is_ok(),
+            None => false,
+        };
+        if in_memory || on_disk {
+            Ok(())
+        } else {
+            Err(CloudError::new(
+                CloudErrorKind::NotFound,
+                format!("secrets {} not found", reference),
+            ))
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use tokio::sync::RwLock;
+
+use crate::deployment::{DeploymentPhase, DeploymentRecord};
+use crate::error;
+
+/// Persists deployment records as one JSON file per deployment
+///
+/// Records are kept in memory and written through to `dir` on every change,
+/// so the status of a deployment survives a restart of the service.
+pub struct DeploymentStore {
+    dir: PathBuf,
+    records: RwLock<HashMap<String, DeploymentRecord>>,
+}
+
+impl DeploymentStore {
+    /// Open the store in `dir`, loading every record already in it
+    pub async fn open(dir: impl Into<PathBuf>) -> Result<Self, error::Error> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(&dir).await.map_err(|e| {
+            error::Error::new("DeploymentStoreOpenFailed", Some(&e.to_string()), 500)
+        })?;
+
+        let mut records = HashMap::new();
+        let mut entries = tokio::fs::read_dir(&dir).await.map_err(|e| {
+            error::Error::new("DeploymentStoreOpenFailed", Some(&e.to_string()), 500)
+        })?;
+        while let Some(entry) = entries.next_entry().await.map_err(|e| {
+            error::Error::new("DeploymentStoreOpenFailed", Some(&e.to_string()), 500)
+        })? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let data = tokio::fs::read(&path).await.map_err(|e| {
+                error::Error::new("DeploymentStoreOpenFailed", Some(&e.to_string()), 500)
+            })?;
+            let record: DeploymentRecord = serde_json::from_slice(&data).map_err(|e| {
+                error::Error::new(
+                    "DeploymentStoreOpenFailed",
+                    Some(&format!("{}: {}", path.display(), e)),
+                    500,
+                )
+            })?;
+            records.insert(record.id.clone(), record);
+        }
+
+        Ok(Self {
+            dir,
+            records: RwLock::new(records),
+        })
+    }
+
+    pub async fn get(&self, id: &str) -> Option<DeploymentRecord> {
+        self.records.read().await.get(id).cloned()
+    }
+
+    /// Find the most recent deployment with the given slug that may still own
+    /// resources, i.e. one that was neither deleted nor rolled back
+    pub async fn find_by_slug(&self, slug: &str) -> Option<DeploymentRecord> {
+        self.find(|r| r.input.deployment_slug == slug).await
+    }
+
+    /// Find the most recent deployment served at the given fully qualified
+    /// name that may still own its DNS records; names differing only in case
+    /// are the same name
+    pub async fn find_by_name(&self, name: &str) -> Option<DeploymentRecord> {
+        self.find(|r| r.plan.record_set.name.eq_ignore_ascii_case(name))
+            .await
+    }
+
+    async fn find<F>(&self, f: F) -> Option<DeploymentRecord>
+    where
+        F: Fn(&DeploymentRecord) -> bool,
+    {
+        self.records
+            .read()
+            .await
+            .values()
+            .filter(|r| {
+                f(r) && !matches!(
+                    r.phase,
+                    DeploymentPhase::Deleted | DeploymentPhase::RolledBack
+                )
+            })
+            .max_by_key(|r| r.created_at)
+            .cloned()
+    }
+
+    pub async fn list_by_phase(&self, phase: DeploymentPhase) -> Vec<DeploymentRecord> {
+        self.records
+            .read()
+            .await
+            .values()
+            .filter(|r| r.phase == phase)
+            .cloned()
+            .collect()
+    }
+
+    /// Deployments a worker had started but not finished provisioning
+    pub async fn list_provisioning(&self) -> Vec<DeploymentRecord> {
+        self.records
+            .read()
+            .await
+            .values()
+            .filter(|r| r.phase.is_provisioning())
+            .cloned()
+            .collect()
+    }
+
+    pub async fn insert(&self, record: DeploymentRecord) -> Result<(), error::Error> {
+        let mut records = self.records.write().await;
+        self.persist(&record).await?;
+        records.insert(record.id.clone(), record);
+        Ok(())
+    }
+
+    /// Apply `f` to the record with the given id and persist the result
+    pub async fn update<F>(&self, id: &str, f: F) -> Result<DeploymentRecord, error::Error>
+    where
+        F: FnOnce(&mut DeploymentRecord),
+    {
+        let mut records = self.records.write().await;
+        let record = records.get_mut(id).ok_or_else(|| {
+            error::Error::new(
+                "DeploymentNotFound",
+                Some(&format!("no deployment with id {}", id)),
+                404,
+            )
+        })?;
+        let mut updated = record.clone();
+        f(&mut updated);
+        self.persist(&updated).await?;
+        *record = updated.clone();
+        Ok(updated)
+    }
+
+    async fn persist(&self, record: &DeploymentRecord) -> Result<(), error::Error> {
+        let body = serde_json::to_vec_pretty(record).map_err(|e| {
+            error::Error::new("DeploymentStoreWriteFailed", Some(&e.to_string()), 500)
+        })?;
+        // write to a temporary file first so a crash never leaves a truncated record
+        let path = self.dir.join(format!("{}.json", record.id));
+        let tmp = self.dir.join(format!("{}.json.tmp", record.id));
+        tokio::fs::write(&tmp, body).await.map_err(|e| {
+            error::Error::new("DeploymentStoreWriteFailed", Some(&e.to_string()), 500)
+        })?;
+        tokio::fs::rename(&tmp, &path).await.map_err(|e| {
+            error::Error::new("DeploymentStoreWriteFailed", Some(&e.to_string()), 500)
+        })?;
+        Ok(())
+    }
+}
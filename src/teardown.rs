@@ -0,0 +1,308 @@
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::okapi::schemars::JsonSchema;
+
+use crate::cloud::CloudProvider;
+use crate::config::{WaitConfig, WaitsConfig};
+use crate::deployment::DeploymentResources;
+use crate::error::{CloudError, CloudErrorKind};
+use crate::waiter::{Check, WaitError, Waiter};
+
+/// Outcome of deleting a single resource
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TeardownStatus {
+    Deleted,
+    /// The resource no longer existed when we tried to delete it
+    NotFound,
+    Failed,
+    /// Not attempted because a resource it depends on could not be deleted
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct ResourceTeardown {
+    pub resource: String,
+    pub id: String,
+    pub status: TeardownStatus,
+    pub error: Option<String>,
+}
+
+impl ResourceTeardown {
+    pub fn new(resource: &str, id: &str, result: Result<(), CloudError>) -> Self {
+        let (status, error) = match result {
+            Ok(()) => (TeardownStatus::Deleted, None),
+            Err(e) if e.is_not_found() => (TeardownStatus::NotFound, None),
+            Err(e) => (TeardownStatus::Failed, Some(e.to_string())),
+        };
+        Self {
+            resource: resource.to_string(),
+            id: id.to_string(),
+            status,
+            error,
+        }
+    }
+
+    pub fn skipped(resource: &str, id: &str, reason: &str) -> Self {
+        Self {
+            resource: resource.to_string(),
+            id: id.to_string(),
+            status: TeardownStatus::Skipped,
+            error: Some(reason.to_string()),
+        }
+    }
+
+    pub fn succeeded(&self) -> bool {
+        matches!(
+            self.status,
+            TeardownStatus::Deleted | TeardownStatus::NotFound
+        )
+    }
+}
+
+/// Delete every resource in `resources` in reverse dependency order
+///
+/// Resources that were deleted (or were already gone) are removed from
+/// `resources`, so calling this again only retries what is left.
+pub async fn teardown(
+    cloud: &dyn CloudProvider,
+    waits: &WaitsConfig,
+    resources: &mut DeploymentResources,
+) -> Vec<ResourceTeardown> {
+    let mut results = vec![];
+
+    for record_set in resources.record_sets.clone() {
+        let result = ResourceTeardown::new(
+            "RecordSet",
+            &format!("{} {}", record_set.name, record_set.record_type),
+            cloud
+                .change_record_set("DELETE", &record_set)
+                .await
+                .map(|_| ()),
+        );
+        if result.succeeded() {
+            resources
+                .record_sets
+                .retain(|r| !r.same_record(&record_set));
+        }
+        results.push(result);
+    }
+
+    for arn in resources.listener_rule_arns.clone() {
+        let result =
+            ResourceTeardown::new("ListenerRule", &arn, cloud.delete_listener_rule(&arn).await);
+        if result.succeeded() {
+            resources.listener_rule_arns.retain(|a| a != &arn);
+        }
+        results.push(result);
+    }
+
+    for arn in resources.listener_arns.clone() {
+        let result = ResourceTeardown::new("Listener", &arn, cloud.delete_listener(&arn).await);
+        if result.succeeded() {
+            resources.listener_arns.retain(|a| a != &arn);
+        }
+        results.push(result);
+    }
+
+    // the target groups can only be deleted once nothing routes to them
+    let mut asg_gone = true;
+    if let Some(name) = resources.auto_scaling_group_name.clone() {
+        let result = ResourceTeardown::new(
+            "AutoScalingGroup",
+            &name,
+            delete_auto_scaling_group(cloud, &waits.auto_scaling_group_deletion, &name).await,
+        );
+        asg_gone = result.succeeded();
+        if asg_gone {
+            resources.auto_scaling_group_name = None;
+        }
+        results.push(result);
+    }
+
+    for arn in resources.target_group_arns.clone() {
+        if !asg_gone
+            || !resources.listener_arns.is_empty()
+            || !resources.listener_rule_arns.is_empty()
+        {
+            results.push(ResourceTeardown::skipped(
+                "TargetGroup",
+                &arn,
+                "auto scaling group, listeners or listener rules still exist",
+            ));
+            continue;
+        }
+        let result =
+            ResourceTeardown::new("TargetGroup", &arn, cloud.delete_target_group(&arn).await);
+        if result.succeeded() {
+            resources.target_group_arns.retain(|a| a != &arn);
+        }
+        results.push(result);
+    }
+
+    // the security group stays in use until the load balancer's network
+    // interfaces are released
+    let mut lb_gone = true;
+    if let Some(arn) = resources.load_balancer_arn.clone() {
+        let result = ResourceTeardown::new(
+            "LoadBalancer",
+            &arn,
+            delete_load_balancer(cloud, &waits.load_balancer, &arn).await,
+        );
+        lb_gone = result.succeeded();
+        if lb_gone {
+            resources.load_balancer_arn = None;
+            resources.load_balancer_dns = None;
+        }
+        results.push(result);
+    }
+
+    // the instances' group stays in use until they have terminated, and
+    // refers to the load balancer's group until it is deleted
+    if let Some(group_id) = resources.instance_security_group_id.clone() {
+        if asg_gone {
+            let result = ResourceTeardown::new(
+                "SecurityGroup",
+                &group_id,
+                cloud.delete_security_group(&group_id).await,
+            );
+            if result.succeeded() {
+                resources.instance_security_group_id = None;
+            }
+            results.push(result);
+        } else {
+            results.push(ResourceTeardown::skipped(
+                "SecurityGroup",
+                &group_id,
+                "auto scaling group still exists",
+            ));
+        }
+    }
+
+    if let Some(group_id) = resources.load_balancer_security_group_id.clone() {
+        if lb_gone && resources.instance_security_group_id.is_none() {
+            let result = ResourceTeardown::new(
+                "SecurityGroup",
+                &group_id,
+                cloud.delete_security_group(&group_id).await,
+            );
+            if result.succeeded() {
+                resources.load_balancer_security_group_id = None;
+            }
+            results.push(result);
+        } else {
+            results.push(ResourceTeardown::skipped(
+                "SecurityGroup",
+                &group_id,
+                "load balancer or instance security group still exists",
+            ));
+        }
+    }
+
+    // the validation records are shared with every other certificate for the
+    // same domain, so they are left in place
+    if let Some(arn) = resources.certificate_arn.clone() {
+        if lb_gone && resources.listener_arns.is_empty() {
+            let result =
+                ResourceTeardown::new("Certificate", &arn, cloud.delete_certificate(&arn).await);
+            if result.succeeded() {
+                resources.certificate_arn = None;
+            }
+            results.push(result);
+        } else {
+            results.push(ResourceTeardown::skipped(
+                "Certificate",
+                &arn,
+                "load balancer or listeners still exist",
+            ));
+        }
+    }
+
+    if let Some(template_id) = resources.launch_template_id.clone() {
+        if asg_gone {
+            let result = ResourceTeardown::new(
+                "LaunchTemplate",
+                &template_id,
+                cloud.delete_launch_template(&template_id).await,
+            );
+            if result.succeeded() {
+                resources.launch_template_id = None;
+            }
+            results.push(result);
+        } else {
+            results.push(ResourceTeardown::skipped(
+                "LaunchTemplate",
+                &template_id,
+                "auto scaling group still exists",
+            ));
+        }
+    }
+
+    // instances read their secrets at boot, so keep them until none are left
+    if let Some(reference) = resources.secrets_reference.clone() {
+        if asg_gone {
+            let result = ResourceTeardown::new(
+                "Secrets",
+                &reference,
+                cloud.secrets().delete(&reference).await,
+            );
+            if result.succeeded() {
+                resources.secrets_reference = None;
+            }
+            results.push(result);
+        } else {
+            results.push(ResourceTeardown::skipped(
+                "Secrets",
+                &reference,
+                "auto scaling group still exists",
+            ));
+        }
+    }
+
+    results
+}
+
+/// Turn a wait for a deleted resource to disappear into the deletion's result
+fn deletion_wait(resource: &str, e: WaitError) -> CloudError {
+    match e {
+        WaitError::Cloud(e) => e,
+        e => CloudError::new(
+            CloudErrorKind::Unavailable,
+            format!("{} was not deleted in time: {}", resource, e.describe()),
+        ),
+    }
+}
+
+/// Force delete the auto scaling group and wait for its instances to drain
+pub async fn delete_auto_scaling_group(
+    cloud: &dyn CloudProvider,
+    wait: &WaitConfig,
+    name: &str,
+) -> Result<(), CloudError> {
+    cloud.delete_auto_scaling_group(name).await?;
+    Waiter::new(wait)
+        .until(|| async {
+            Ok(match cloud.auto_scaling_group_exists(name).await? {
+                false => Check::Done(()),
+                true => Check::Waiting("instances still terminating".to_string()),
+            })
+        })
+        .await
+        .map_err(|e| deletion_wait(&format!("auto scaling group {}", name), e))
+}
+
+/// Delete the load balancer and wait until it has disappeared
+pub async fn delete_load_balancer(
+    cloud: &dyn CloudProvider,
+    wait: &WaitConfig,
+    arn: &str,
+) -> Result<(), CloudError> {
+    cloud.delete_load_balancer(arn).await?;
+    Waiter::new(wait)
+        .until(|| async {
+            Ok(match cloud.load_balancer_state(arn).await? {
+                None => Check::Done(()),
+                Some(state) => Check::Waiting(state.to_string()),
+            })
+        })
+        .await
+        .map_err(|e| deletion_wait(&format!("load balancer {}", arn), e))
+}
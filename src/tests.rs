@@ -0,0 +1,1309 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Once};
+
+use base64::prelude::*;
+use rocket::figment::providers::{Format, Toml};
+use rocket::figment::Figment;
+use rocket::http::{ContentType, Status};
+use rocket::local::asynchronous::Client;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+use crate::cloud::CloudProvider;
+use crate::config::Config;
+use crate::deployment::{DeploymentPhase, DeploymentRecord, RecordSet};
+use crate::error::{CloudError, CloudErrorKind, Error};
+use crate::fake::FakeCloud;
+use crate::jobs::{Job, JobQueue, Work};
+use crate::plan::{
+    IngressSource, ListenerAction, ListenerPlan, LoadBalancerPlan, Scheme, SecurityGroupPlan,
+    HTTPS_PORT,
+};
+use crate::store::DeploymentStore;
+use crate::{AppState, DeployAWSInput};
+
+mod emulator;
+
+const CONFIG: &str = r#"
+default_environment = "test"
+
+[environments.test]
+region = "us-west-1"
+vpc_id = "vpc-test"
+instance_subnets = ["subnet-instances"]
+public_subnets = ["subnet-a", "subnet-b"]
+private_subnets = ["subnet-private-a", "subnet-private-b"]
+hosted_zone_id = "ZTEST"
+private_hosted_zone_id = "ZPRIVATE"
+image_id = "ami-test"
+
+[environments.test.secrets]
+backend = "local"
+
+[environments.test.retry]
+base_delay_ms = 1
+max_delay_ms = 5
+
+[environments.test.waits]
+load_balancer = { timeout_ms = 50, poll_interval_ms = 5 }
+instances = { timeout_ms = 50, poll_interval_ms = 5 }
+auto_scaling_group_deletion = { timeout_ms = 50, poll_interval_ms = 5 }
+targets = { timeout_ms = 50, poll_interval_ms = 5 }
+record_set = { timeout_ms = 50, poll_interval_ms = 5 }
+certificate = { timeout_ms = 50, poll_interval_ms = 5 }
+"#;
+
+/// The configuration in `toml`
+fn config(toml: &str) -> Config {
+    Config::load(&Figment::from(Toml::string(toml))).expect("valid config")
+}
+
+/// The only environment of `CONFIG`, provisioned in `cloud`
+fn providers(cloud: Arc<FakeCloud>) -> HashMap<String, Arc<dyn CloudProvider>> {
+    let mut providers: HashMap<String, Arc<dyn CloudProvider>> = HashMap::new();
+    providers.insert("test".to_string(), cloud);
+    providers
+}
+
+/// The secrets every deployment stores are read from the environment, which
+/// is shared by tests running in parallel, so it is only set once
+fn set_secrets() {
+    static SECRETS: Once = Once::new();
+    SECRETS.call_once(|| {
+        std::env::set_var("TURSO_TOKEN", "turso-token");
+        std::env::set_var("FILE_ENCRYPTION_KEY", "file-encryption-key");
+    });
+}
+
+/// A state or client along with the directory of its deployment store, which
+/// is removed once the test is done with it
+struct WithStore<T> {
+    inner: T,
+    _dir: TempDir,
+}
+
+impl<T> Deref for WithStore<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// A deployment store in a fresh directory
+async fn store() -> (DeploymentStore, TempDir) {
+    let dir = tempfile::tempdir().expect("temporary directory");
+    let store = DeploymentStore::open(dir.path())
+        .await
+        .expect("store opens");
+    (store, dir)
+}
+
+/// The state of the service configured with `config`, provisioning each
+/// environment through `providers`, with a fresh deployment store
+async fn state_with(
+    config: Config,
+    providers: HashMap<String, Arc<dyn CloudProvider>>,
+) -> WithStore<AppState> {
+    set_secrets();
+    let (store, dir) = store().await;
+    let state = AppState::new(config, providers, store)
+        .await
+        .expect("valid environments");
+    WithStore {
+        inner: state,
+        _dir: dir,
+    }
+}
+
+/// The state of the service provisioning into `cloud`
+async fn state(cloud: Arc<FakeCloud>) -> WithStore<AppState> {
+    state_with(config(CONFIG), providers(cloud)).await
+}
+
+/// A client for the service configured with `config`, provisioning each
+/// environment through `providers`, with a fresh deployment store
+async fn client_with(
+    config: Config,
+    providers: HashMap<String, Arc<dyn CloudProvider>>,
+) -> WithStore<Client> {
+    let WithStore { inner: state, _dir } = state_with(config, providers).await;
+    let jobs = JobQueue::start(state.clone(), 1, 10);
+    let client = Client::tracked(crate::rocket(state, jobs))
+        .await
+        .expect("valid rocket");
+    WithStore {
+        inner: client,
+        _dir,
+    }
+}
+
+/// A client for the service provisioning into `cloud`
+async fn client(cloud: Arc<FakeCloud>) -> WithStore<Client> {
+    client_with(config(CONFIG), providers(cloud)).await
+}
+
+fn input(slug: &str) -> Value {
+    json!({
+        "flake_url": "github:example/app#flakery",
+        "instance_type": "t3.small",
+        "deployment_slug": slug,
+        "subdomain_prefix": slug,
+        "template_id": "template",
+        "targets": [{"port": 8080}],
+    })
+}
+
+async fn post(client: &Client, uri: &str, body: &Value) -> (Status, Value) {
+    let response = client
+        .post(uri)
+        .header(ContentType::JSON)
+        .body(body.to_string())
+        .dispatch()
+        .await;
+    let status = response.status();
+    (status, response.into_json().await.unwrap_or(Value::Null))
+}
+
+/// Poll the deployment until a worker has finished with it
+async fn wait_for(client: &Client, id: &str) -> Value {
+    for _ in 0..600 {
+        let record: Value = client
+            .get(format!("/deploy/aws/{}", id))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .expect("deployment record");
+        if matches!(
+            record["phase"].as_str(),
+            Some("Ready") | Some("Failed") | Some("RolledBack") | Some("Deleted")
+        ) {
+            return record;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("deployment {} did not finish", id);
+}
+
+/// Delete a deployment and wait for its resources to be torn down
+async fn delete(client: &Client, id: &str) -> Value {
+    let response = client
+        .delete(format!("/deploy/aws/{}", id))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Accepted);
+    let output: Value = response.into_json().await.expect("delete output");
+    assert_eq!(output["phase"], "Deleting", "{}", output);
+    wait_for(client, output["id"].as_str().unwrap()).await
+}
+
+#[rocket::async_test]
+async fn plan_does_not_touch_the_cloud() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+    // nor does it look anything up
+    for operation in ["hosted_zone_name", "subnet_is_public"] {
+        cloud.fail(operation);
+    }
+
+    let (status, plan) = post(&client, "/deploy/aws/plan", &input("planned")).await;
+    assert_eq!(status, Status::Ok, "{}", plan);
+    assert_eq!(plan["environment"], "test");
+    assert_eq!(plan["listeners"][0]["port"], 8080);
+    assert!(cloud.resources().is_empty());
+}
+
+#[rocket::async_test]
+async fn create_provisions_and_delete_tears_down() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let (status, output) = post(&client, "/deploy/aws/create", &input("app")).await;
+    assert_eq!(status, Status::Accepted);
+    assert_eq!(output["fqdn"], "app.example.test");
+    let id = output["id"].as_str().expect("deployment id");
+
+    let record = wait_for(&client, id).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    let resources = cloud.resources();
+    assert_eq!(resources.launch_templates.len(), 1);
+    assert_eq!(resources.auto_scaling_groups["app-asg"].len(), 1);
+    assert_eq!(resources.listeners.len(), 1);
+    let key = ("app.example.test".to_string(), "A".to_string());
+    assert_eq!(resources.record_sets[&key], "app-lb.elb.fake");
+    // the load balancer has no IPv6 address to alias
+    assert_eq!(resources.record_sets.len(), 1);
+    // only where to find the secrets is handed to instances
+    assert_eq!(
+        record["plan"]["launch_template"]["tags"]["secrets_reference"],
+        "/flakery/app"
+    );
+
+    let record = delete(&client, id).await;
+    assert_eq!(record["phase"], "Deleted", "{}", record);
+    let teardown = record["teardown"].as_array().unwrap();
+    assert!(
+        teardown.iter().all(|r| r["status"] == "Deleted"),
+        "{}",
+        record
+    );
+    assert!(cloud.resources().is_empty(), "{:?}", cloud.resources());
+
+    // deleting it again changes nothing
+    let response = client
+        .delete(format!("/deploy/aws/{}", id))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Accepted);
+    let output: Value = response.into_json().await.unwrap();
+    assert_eq!(output["phase"], "Deleted", "{}", output);
+    let again = wait_for(&client, id).await;
+    assert_eq!(again["teardown"], record["teardown"]);
+}
+
+#[rocket::async_test]
+async fn failed_step_rolls_back_everything_before_it() {
+    let cloud = Arc::new(FakeCloud::new());
+    cloud.fail("create_listener");
+    let client = client(cloud.clone()).await;
+
+    let (status, output) = post(&client, "/deploy/aws/create", &input("broken")).await;
+    assert_eq!(status, Status::Accepted);
+
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "RolledBack", "{}", record);
+    assert!(record["error"]
+        .as_str()
+        .unwrap()
+        .contains("ListenerCreationFailed"));
+    // an unclassified failure is not worth retrying
+    assert_eq!(record["retries"], json!({}));
+    assert!(record["retry_after"].is_null(), "{}", record);
+    assert!(cloud.resources().is_empty(), "{:?}", cloud.resources());
+
+    // there is nothing left to delete
+    let response = client
+        .delete(format!("/deploy/aws/{}", record["id"].as_str().unwrap()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Accepted);
+    let output: Value = response.into_json().await.unwrap();
+    assert_eq!(output["phase"], "RolledBack", "{}", output);
+}
+
+#[rocket::async_test]
+async fn rollback_keeps_what_a_remaining_resource_still_uses() {
+    let cloud = Arc::new(FakeCloud::new());
+    cloud.fail("create_listener");
+    cloud.fail("delete_auto_scaling_group");
+    let client = client(cloud.clone()).await;
+
+    let (_, output) = post(&client, "/deploy/aws/create", &input("stuck")).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Failed", "{}", record);
+    let statuses = record["rollback"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| {
+            (
+                r["resource"].as_str().unwrap(),
+                r["status"].as_str().unwrap(),
+            )
+        })
+        .collect::<Vec<(&str, &str)>>();
+    assert_eq!(
+        statuses,
+        [
+            ("LoadBalancer", "Deleted"),
+            ("TargetGroup", "Deleted"),
+            ("AutoScalingGroup", "Failed"),
+            ("LaunchTemplate", "Skipped"),
+            ("SecurityGroup", "Skipped"),
+            ("SecurityGroup", "Skipped"),
+            ("Secrets", "Skipped"),
+        ]
+    );
+    // the running instances keep their secrets and launch template
+    let resources = &record["resources"];
+    assert_eq!(resources["secrets_reference"], "/flakery/stuck");
+    assert!(resources["launch_template_id"].is_string());
+    assert_eq!(cloud.resources().launch_templates.len(), 1);
+}
+
+#[rocket::async_test]
+async fn live_slug_is_rejected() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let (status, _) = post(&client, "/deploy/aws/create", &input("taken")).await;
+    assert_eq!(status, Status::Accepted);
+    let (status, error) = post(&client, "/deploy/aws/create", &input("taken")).await;
+    assert_eq!(status, Status::Conflict);
+    assert!(matches!(
+        error["err"].as_str(),
+        Some("DeploymentSlugTaken") | Some("DeploymentInProgress")
+    ));
+}
+
+#[rocket::async_test]
+async fn a_name_is_served_by_one_deployment_at_a_time() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("blue");
+    body["subdomain_prefix"] = json!("www");
+    let (status, output) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Accepted);
+    assert_eq!(output["fqdn"], "www.example.test");
+    let blue = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(blue["phase"], "Ready", "{}", blue);
+
+    let mut body = input("green");
+    body["subdomain_prefix"] = json!("www");
+    let (status, error) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Conflict);
+    assert_eq!(error["err"], "DomainNameTaken");
+    // DNS names are case-insensitive
+    body["subdomain_prefix"] = json!("WWW");
+    let (status, error) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Conflict, "{}", error);
+    assert_eq!(error["err"], "DomainNameTaken");
+    body["subdomain_prefix"] = json!("www");
+
+    // a record someone else has since pointed elsewhere is left alone
+    let mut record_set: RecordSet =
+        serde_json::from_value(blue["resources"]["record_sets"][0].clone()).unwrap();
+    record_set.alias_target.as_mut().unwrap().dns_name = "other-lb.elb.fake".to_string();
+    cloud
+        .change_record_set("UPSERT", &record_set)
+        .await
+        .unwrap();
+    let record = delete(&client, blue["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Deleted", "{}", record);
+    let key = (
+        "www.example.test".to_string(),
+        record_set.record_type.clone(),
+    );
+    assert_eq!(cloud.resources().record_sets[&key], "other-lb.elb.fake");
+
+    // the name is free once its deployment is deleted
+    let (status, output) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Accepted, "{}", output);
+    let green = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(green["phase"], "Ready", "{}", green);
+    assert_eq!(cloud.resources().record_sets[&key], "green-lb.elb.fake");
+}
+
+#[rocket::async_test]
+async fn a_name_being_claimed_is_reported_as_such() {
+    let state = state(Arc::new(FakeCloud::new())).await;
+    let name = state.name_locks.acquire("www.example.test").unwrap();
+    let jobs = JobQueue::start(state.clone(), 1, 10);
+    let client = Client::tracked(crate::rocket(state.clone(), jobs))
+        .await
+        .unwrap();
+
+    let mut body = input("blue");
+    body["subdomain_prefix"] = json!("www");
+    let (status, error) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Conflict);
+    assert_eq!(error["err"], "DomainNameInProgress", "{}", error);
+    assert!(error["msg"].as_str().unwrap().contains("www.example.test"));
+
+    drop(name);
+    let (status, output) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Accepted, "{}", output);
+}
+
+#[rocket::async_test]
+async fn disallowed_instance_type_is_rejected() {
+    let client = client(Arc::new(FakeCloud::new())).await;
+
+    let mut body = input("big");
+    body["instance_type"] = json!("p4d.24xlarge");
+    let (status, error) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    assert_eq!(error["fields"][0]["field"], "instance_type", "{}", error);
+    assert_eq!(error["fields"][0]["code"], "InvalidInstanceType");
+
+    // reported along with every other invalid field
+    body["template_id"] = json!("t".repeat(300));
+    body["min_size"] = json!(-1);
+    let (status, error) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    let mut fields = error["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| (f["field"].as_str().unwrap(), f["code"].as_str().unwrap()))
+        .collect::<Vec<(&str, &str)>>();
+    fields.sort();
+    assert_eq!(
+        fields,
+        [
+            ("instance_type", "InvalidInstanceType"),
+            ("min_size", "OutOfRange"),
+            ("template_id", "TagValueTooLong"),
+        ]
+    );
+}
+
+#[rocket::async_test]
+async fn each_target_gets_its_own_target_group_and_listener() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("multi");
+    body["targets"] = json!([{"port": 8080}, {"port": 9090}]);
+    let (status, output) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Accepted);
+
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    let resources = cloud.resources();
+    let names = resources.target_groups.values().collect::<Vec<&String>>();
+    assert_eq!(names.len(), 2);
+    assert!(names.contains(&&"multi-tg-8080".to_string()), "{:?}", names);
+    assert!(names.contains(&&"multi-tg-9090".to_string()), "{:?}", names);
+    assert_eq!(resources.listeners.len(), 2);
+    assert_eq!(resources.auto_scaling_groups["multi-asg"].len(), 2);
+}
+
+#[rocket::async_test]
+async fn routed_targets_share_one_listener() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("routed");
+    body["targets"] = json!([
+        {"port": 8000},
+        {"port": 8080, "path_pattern": "/api/*"},
+        {"port": 9000, "path_pattern": "/ws/*", "host_header": "ws.example.test"},
+    ]);
+    let (status, plan) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(status, Status::Ok, "{}", plan);
+    let listeners = plan["listeners"].as_array().unwrap();
+    assert_eq!(listeners.len(), 2);
+    assert_eq!(listeners[0]["port"], 80);
+    assert_eq!(listeners[0]["action"], "not_found");
+    assert_eq!(
+        listeners[0]["rules"][1],
+        json!({
+            "host_header": "ws.example.test",
+            "path_pattern": "/ws/*",
+            "target_group": "routed-tg-9000",
+        })
+    );
+    // targets that aren't routed keep their own listener
+    assert_eq!(listeners[1]["port"], 8000);
+
+    // over HTTPS the first target is the default of the shared listener
+    body["https"] = json!(true);
+    let (_, plan) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(plan["listeners"][0]["port"], 443);
+    assert_eq!(
+        plan["listeners"][0]["action"],
+        json!({"forward": "routed-tg-8000"})
+    );
+    assert_eq!(plan["listeners"][0]["rules"].as_array().unwrap().len(), 2);
+    assert_eq!(plan["listeners"][1]["port"], 80);
+
+    body["https"] = json!(false);
+    let (_, output) = post(&client, "/deploy/aws/create", &body).await;
+    let id = output["id"].as_str().unwrap();
+    let record = wait_for(&client, id).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    let resources = cloud.resources();
+    assert_eq!(resources.listeners.len(), 2);
+    let mut rules = resources
+        .listener_rules
+        .values()
+        .map(|r| (r.priority, r.path_pattern.as_deref().unwrap()))
+        .collect::<Vec<(i64, &str)>>();
+    rules.sort();
+    assert_eq!(rules, [(1, "/api/*"), (2, "/ws/*")]);
+
+    let record = delete(&client, id).await;
+    assert_eq!(record["phase"], "Deleted", "{}", record);
+    assert!(cloud.resources().is_empty(), "{:?}", cloud.resources());
+}
+
+#[rocket::async_test]
+async fn routes_must_be_valid_and_distinct() {
+    let client = client(Arc::new(FakeCloud::new())).await;
+
+    let mut body = input("clash");
+    body["targets"] = json!([
+        {"port": 80},
+        {"port": 8080, "path_pattern": "/api/*"},
+        {"port": 9000, "path_pattern": "/api/*"},
+        {"port": 9001, "path_pattern": "api"},
+        {"port": 9002, "host_header": "bad host"},
+    ]);
+    let (status, error) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    let fields = error["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| (f["field"].as_str().unwrap(), f["code"].as_str().unwrap()))
+        .collect::<Vec<(&str, &str)>>();
+    assert_eq!(
+        fields,
+        [
+            ("targets[0].port", "ReservedListenerPort"),
+            ("targets[2]", "DuplicateRoute"),
+            ("targets[3].path_pattern", "InvalidPathPattern"),
+            ("targets[4].host_header", "InvalidHostHeader"),
+        ]
+    );
+}
+
+#[rocket::async_test]
+async fn https_deployments_get_a_certificate_and_redirect_http() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("secure");
+    body["https"] = json!(true);
+    body["targets"] = json!([{"port": 8080}, {"port": 9090}]);
+    let (status, output) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Accepted, "{}", output);
+    let id = output["id"].as_str().unwrap();
+
+    let record = wait_for(&client, id).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    let resources = cloud.resources();
+    let (certificate_arn, certificate) = resources.certificates.iter().next().unwrap();
+    assert_eq!(certificate.domain_name, "secure.example.test");
+    let validation = certificate.validation_record.clone();
+    assert_eq!(
+        resources.record_sets[&(validation.name.clone(), validation.record_type.clone())],
+        validation.value
+    );
+    let mut listeners = resources
+        .listeners
+        .values()
+        .map(|l| (l.port, l.protocol.as_str(), l.certificate_arn.as_ref()))
+        .collect::<Vec<_>>();
+    listeners.sort();
+    assert_eq!(
+        listeners,
+        [
+            (80, "HTTP", None),
+            (443, "HTTPS", Some(certificate_arn)),
+            (9090, "HTTPS", Some(certificate_arn)),
+        ]
+    );
+    let redirect = resources.listeners.values().find(|l| l.port == 80).unwrap();
+    assert_eq!(redirect.target_group_arn, None);
+    assert_eq!(
+        record["resources"]["certificate_arn"].as_str(),
+        Some(certificate_arn.as_str())
+    );
+
+    let record = delete(&client, id).await;
+    assert_eq!(record["phase"], "Deleted", "{}", record);
+    // the validation record is shared with every certificate for the name
+    let key = (validation.name.clone(), validation.record_type.clone());
+    let mut resources = cloud.resources();
+    assert_eq!(
+        resources.record_sets.remove(&key),
+        Some(validation.value.clone())
+    );
+    assert!(resources.is_empty(), "{:?}", resources);
+
+    // so rolling back another deployment of the name leaves it in place too
+    cloud.fail("create_listener");
+    let (_, output) = post(&client, "/deploy/aws/create", &body).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "RolledBack", "{}", record);
+    let mut resources = cloud.resources();
+    assert_eq!(resources.record_sets.remove(&key), Some(validation.value));
+    assert!(resources.is_empty(), "{:?}", resources);
+
+    // the other targets can't take the ports of the HTTPS listeners
+    body["targets"] = json!([{"port": 8080}, {"port": 443}]);
+    let (status, error) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    assert_eq!(error["fields"][0]["code"], "ReservedListenerPort");
+}
+
+#[rocket::async_test]
+async fn instances_are_only_reachable_through_the_load_balancer() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let (_, output) = post(&client, "/deploy/aws/create", &input("private")).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    let lb_sg = record["resources"]["load_balancer_security_group_id"]
+        .as_str()
+        .unwrap();
+    let instance_sg = record["resources"]["instance_security_group_id"]
+        .as_str()
+        .unwrap();
+
+    let resources = cloud.resources();
+    let sources = |group: &str| {
+        resources.security_groups[group]
+            .iter()
+            .map(|rule| (rule.from_port, rule.source.clone()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        sources(lb_sg),
+        [(8080, IngressSource::Cidr("0.0.0.0/0".to_string()))]
+    );
+    assert_eq!(
+        sources(instance_sg),
+        [(8080, IngressSource::SecurityGroup(lb_sg.to_string()))]
+    );
+    assert_eq!(
+        resources.launch_template_security_groups["private-lt"],
+        instance_sg
+    );
+}
+
+#[rocket::async_test]
+async fn internal_deployments_are_private_and_allowlisted() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("tool");
+    body["scheme"] = json!("internal");
+    body["ingress_cidrs"] = json!(["10.0.0.0/8"]);
+    let (status, plan) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(status, Status::Ok, "{}", plan);
+    assert_eq!(plan["load_balancer"]["scheme"], "internal");
+    assert_eq!(
+        plan["load_balancer"]["subnets"],
+        json!(["subnet-private-a", "subnet-private-b"])
+    );
+    assert_eq!(plan["record_set"]["hosted_zone_id"], "ZPRIVATE");
+    let rules = plan["load_balancer_security_group"]["ingress"]
+        .as_array()
+        .unwrap();
+    assert_eq!(rules.len(), 1);
+    assert_eq!(rules[0]["source"], json!({"cidr": "10.0.0.0/8"}));
+
+    body["ingress_cidrs"] = json!(["10.0.0.0/33"]);
+    let (status, error) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    assert_eq!(error["fields"][0]["field"], "ingress_cidrs[0]");
+    assert_eq!(error["fields"][0]["code"], "InvalidCidr");
+}
+
+#[rocket::async_test]
+async fn internal_https_needs_a_private_zone_with_the_public_domain() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("tool");
+    body["scheme"] = json!("internal");
+    body["https"] = json!(true);
+    let (status, error) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    assert_eq!(error["fields"][0]["field"], "https");
+    assert_eq!(error["fields"][0]["code"], "CertificateNotValidatable");
+
+    // a split-horizon private zone serves the same domain as the public one
+    let config = CONFIG.replace(
+        r#"private_hosted_zone_id = "ZPRIVATE""#,
+        r#"private_hosted_zone_id = "ZSPLIT""#,
+    );
+    let client = client_with(self::config(&config), providers(cloud)).await;
+    let (status, plan) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(status, Status::Ok, "{}", plan);
+    assert_eq!(plan["certificate"]["domain_name"], "tool.example.test");
+    assert_eq!(plan["certificate"]["hosted_zone_id"], "ZTEST");
+    assert_eq!(plan["record_set"]["hosted_zone_id"], "ZSPLIT");
+}
+
+#[rocket::async_test]
+async fn load_balancer_subnets_must_suit_the_scheme() {
+    let config = CONFIG.replace(
+        r#"public_subnets = ["subnet-a", "subnet-b"]"#,
+        r#"public_subnets = ["subnet-a", "subnet-private-c"]"#,
+    );
+    let providers = providers(Arc::new(FakeCloud::new()));
+    let (store, _dir) = store().await;
+
+    // checked once, before the service takes any requests
+    let Err(error) = AppState::new(self::config(&config), providers, store).await else {
+        panic!("public subnets routing nowhere were accepted");
+    };
+    assert_eq!(error.err, "SubnetSchemeMismatch");
+    assert!(error.to_string().contains("subnet-private-c"), "{}", error);
+}
+
+/// A client whose environment shares a load balancer provisioned in `cloud`
+/// ahead of time, and the id of that load balancer's security group
+async fn shared_load_balancer_client(
+    cloud: Arc<FakeCloud>,
+    scheme: Scheme,
+) -> (WithStore<Client>, String) {
+    let security_group_id = cloud
+        .create_security_group(&SecurityGroupPlan {
+            name: "shared-lb-sg".to_string(),
+            description: "Shared load balancer".to_string(),
+            vpc_id: "vpc-test".to_string(),
+            ingress: vec![],
+        })
+        .await
+        .unwrap();
+    let load_balancer = cloud
+        .create_load_balancer(
+            &LoadBalancerPlan {
+                name: "shared".to_string(),
+                scheme,
+                subnets: vec!["subnet-a".to_string(), "subnet-b".to_string()],
+            },
+            &security_group_id,
+        )
+        .await
+        .unwrap();
+    let listener_arn = cloud
+        .create_listener(
+            &load_balancer.arn,
+            &ListenerPlan {
+                protocol: "HTTPS".to_string(),
+                port: HTTPS_PORT,
+                action: ListenerAction::NotFound,
+                rules: vec![],
+            },
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+    let config = format!(
+        "{}\n[environments.test.shared_load_balancer]\narn = {:?}\nlistener_arn = {:?}\nsecurity_group_id = {:?}\nscheme = {:?}\n",
+        CONFIG,
+        load_balancer.arn,
+        listener_arn,
+        security_group_id,
+        scheme.as_str()
+    );
+    (
+        client_with(self::config(&config), providers(cloud)).await,
+        security_group_id,
+    )
+}
+
+#[rocket::async_test]
+async fn shared_load_balancer_routes_by_host_name() {
+    let cloud = Arc::new(FakeCloud::new());
+    let (client, shared_sg) =
+        shared_load_balancer_client(cloud.clone(), Scheme::InternetFacing).await;
+
+    let mut ids = HashMap::new();
+    for slug in ["one", "two"] {
+        let mut body = input(slug);
+        body["shared_load_balancer"] = json!(true);
+        let (status, output) = post(&client, "/deploy/aws/create", &body).await;
+        assert_eq!(status, Status::Accepted, "{}", output);
+        let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+        assert_eq!(record["phase"], "Ready", "{}", record);
+        assert!(record["resources"]["load_balancer_arn"].is_null());
+        assert!(record["resources"]["load_balancer_security_group_id"].is_null());
+        let instance_sg = record["resources"]["instance_security_group_id"]
+            .as_str()
+            .unwrap();
+        let rules = &cloud.resources().security_groups[instance_sg];
+        assert_eq!(rules.len(), 1);
+        assert_eq!(
+            rules[0].source,
+            IngressSource::SecurityGroup(shared_sg.clone())
+        );
+        ids.insert(slug, record["id"].as_str().unwrap().to_string());
+    }
+
+    let resources = cloud.resources();
+    assert_eq!(resources.load_balancers.len(), 1);
+    assert_eq!(resources.listeners.len(), 1);
+    let rules = resources
+        .listener_rules
+        .values()
+        .map(|r| (r.host_header.as_deref().unwrap(), r.priority))
+        .collect::<HashMap<&str, i64>>();
+    assert_eq!(
+        rules,
+        HashMap::from([("one.example.test", 1), ("two.example.test", 2)])
+    );
+    let key = ("two.example.test".to_string(), "A".to_string());
+    assert_eq!(resources.record_sets[&key], "shared.elb.fake");
+
+    // the rule is removed with its deployment, freeing its priority
+    let record = delete(&client, &ids["one"]).await;
+    assert_eq!(record["phase"], "Deleted", "{}", record);
+    assert_eq!(cloud.resources().listener_rules.len(), 1);
+    let mut body = input("three");
+    body["shared_load_balancer"] = json!(true);
+    let (_, output) = post(&client, "/deploy/aws/create", &body).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    let rule = &record["resources"]["listener_rule_arns"][0];
+    assert_eq!(
+        cloud.resources().listener_rules[rule.as_str().unwrap()].priority,
+        1
+    );
+}
+
+#[rocket::async_test]
+async fn an_internal_shared_load_balancer_serves_from_the_private_zone() {
+    let cloud = Arc::new(FakeCloud::new());
+    let (client, _) = shared_load_balancer_client(cloud.clone(), Scheme::Internal).await;
+
+    // the scheme follows the shared load balancer when it isn't given
+    let mut body = input("tool");
+    body["shared_load_balancer"] = json!(true);
+    let (status, output) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Accepted, "{}", output);
+    assert_eq!(output["fqdn"], "tool.internal.example.test");
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    assert_eq!(record["plan"]["record_set"]["hosted_zone_id"], "ZPRIVATE");
+    let key = ("tool.internal.example.test".to_string(), "A".to_string());
+    assert_eq!(cloud.resources().record_sets[&key], "shared.elb.fake");
+}
+
+#[rocket::async_test]
+async fn shared_load_balancer_needs_one_target_under_the_wildcard() {
+    let cloud = Arc::new(FakeCloud::new());
+    let (client, _) = shared_load_balancer_client(cloud.clone(), Scheme::InternetFacing).await;
+
+    let mut body = input("wide");
+    body["shared_load_balancer"] = json!(true);
+    body["subdomain_prefix"] = json!("api.wide");
+    body["targets"] = json!([{"port": 8080}, {"port": 9000}]);
+    body["https"] = json!(true);
+    let (status, error) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    let fields = error["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| (f["field"].as_str().unwrap(), f["code"].as_str().unwrap()))
+        .collect::<Vec<(&str, &str)>>();
+    assert_eq!(
+        fields,
+        [
+            ("targets", "SharedLoadBalancerSingleTarget"),
+            ("subdomain_prefix", "NotCoveredByWildcardCertificate"),
+            ("https", "NotSupportedWithSharedLoadBalancer"),
+        ]
+    );
+
+    // an environment without one can't share it
+    let client = self::client(cloud).await;
+    let mut body = input("alone");
+    body["shared_load_balancer"] = json!(true);
+    let (status, error) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    assert_eq!(
+        error["fields"][0]["code"],
+        "SharedLoadBalancerNotConfigured"
+    );
+}
+
+#[rocket::async_test]
+async fn files_need_their_content() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("files");
+    body["files"] = json!([{"path": "/etc/app.env"}]);
+    let (status, _) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    assert!(cloud.resources().is_empty());
+
+    // modes are octal digits only
+    for mode in ["+777", "0800", "06000"] {
+        body["files"] = json!([{"path": "/etc/app.env", "content": "KEY=value", "mode": mode}]);
+        let (status, error) = post(&client, "/deploy/aws/plan", &body).await;
+        assert_eq!(status, Status::UnprocessableEntity, "{}", mode);
+        assert_eq!(error["fields"][0]["code"], "InvalidFileMode", "{}", error);
+    }
+
+    // the content is never persisted, yet the record can be read back
+    body["files"] = json!([{"path": "/etc/app.env", "content": "KEY=value"}]);
+    let input: DeployAWSInput = serde_json::from_value(body).unwrap();
+    let plan = state(cloud).await.plan(&input).unwrap();
+    let (store, dir) = self::store().await;
+    let record = DeploymentRecord::new("with-files".to_string(), input.stored(), plan);
+    store.insert(record).await.unwrap();
+    let persisted = std::fs::read_to_string(dir.path().join("with-files.json")).unwrap();
+    assert!(!persisted.contains("KEY=value"), "{}", persisted);
+    let store = DeploymentStore::open(dir.path()).await.unwrap();
+    let record = store.get("with-files").await.unwrap();
+    assert_eq!(record.input.files.unwrap()[0].path, "/etc/app.env");
+}
+
+#[rocket::async_test]
+async fn files_are_only_encrypted_once_provisioned() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("encrypted");
+    body["files"] = json!([{"path": "/etc/app.env", "content": "KEY=value"}]);
+    let (status, plan) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(status, Status::Ok, "{}", plan);
+    let (_, again) = post(&client, "/deploy/aws/plan", &body).await;
+    assert_eq!(plan, again);
+    assert_eq!(plan["launch_template"]["files"][0]["size"], 9);
+
+    let (_, output) = post(&client, "/deploy/aws/create", &body).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    let user_data = &cloud.resources().launch_template_user_data["encrypted-lt"];
+    let user_data = BASE64_STANDARD.decode(user_data).unwrap();
+    let user_data: Value = serde_json::from_slice(&user_data).unwrap();
+    assert_eq!(user_data["files"][0]["path"], "/etc/app.env");
+    assert_ne!(user_data["files"][0]["content"], "KEY=value");
+}
+
+#[rocket::async_test]
+async fn invalid_input_lists_every_invalid_field() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("Not_A_Slug");
+    body["flake_url"] = json!("not a flake");
+    body["subdomain_prefix"] = json!("");
+    body["min_size"] = json!(3);
+    body["max_size"] = json!(2);
+    body["targets"] = json!([{"port": 8080}, {"port": 8080}, {"port": 70000}]);
+    let (status, error) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::UnprocessableEntity);
+    assert_eq!(error["err"], "ValidationFailed");
+    let fields = error["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| (f["field"].as_str().unwrap(), f["code"].as_str().unwrap()))
+        .collect::<Vec<(&str, &str)>>();
+    assert_eq!(
+        fields,
+        vec![
+            ("deployment_slug", "InvalidDeploymentSlug"),
+            ("flake_url", "InvalidFlakeUrl"),
+            ("subdomain_prefix", "Required"),
+            ("min_size", "MinSizeAboveMaxSize"),
+            ("targets[1].port", "DuplicateTargetPort"),
+            ("targets[2].port", "PortOutOfRange"),
+        ]
+    );
+    assert!(cloud.resources().is_empty());
+
+    let (status, plan) = post(&client, "/deploy/aws/plan", &input("valid")).await;
+    assert_eq!(status, Status::Ok);
+    assert!(plan.get("fields").is_none());
+
+    let openapi: Value = client
+        .get("/openapi.json")
+        .dispatch()
+        .await
+        .into_json()
+        .await
+        .expect("openapi document");
+    let unprocessable = &openapi["paths"]["/deploy/aws/create"]["post"]["responses"]["422"];
+    assert!(!unprocessable["content"]["application/json"]["schema"].is_null());
+    assert!(!openapi["components"]["schemas"]["FieldError"].is_null());
+}
+
+#[rocket::async_test]
+async fn long_slugs_get_shortened_names() {
+    let client = client(Arc::new(FakeCloud::new())).await;
+
+    let slug = "a-rather-long-deployment-slug-for-testing";
+    let (status, plan) = post(&client, "/deploy/aws/plan", &input(slug)).await;
+    assert_eq!(status, Status::Ok);
+    let load_balancer = plan["load_balancer"]["name"].as_str().unwrap();
+    let target_group = plan["target_groups"][0]["name"].as_str().unwrap();
+    assert!(load_balancer.len() <= 32 && load_balancer.ends_with("-lb"));
+    assert!(target_group.len() <= 32 && target_group.ends_with("-tg-8080"));
+    assert_eq!(plan["auto_scaling_group"]["name"], format!("{}-asg", slug));
+
+    let (_, again) = post(&client, "/deploy/aws/plan", &input(slug)).await;
+    assert_eq!(again["load_balancer"]["name"], load_balancer);
+}
+
+#[rocket::async_test]
+async fn fake_cloud_enforces_unique_names() {
+    let cloud = FakeCloud::new();
+    let plan = crate::plan::TargetGroupPlan {
+        name: "app".to_string(),
+        protocol: "HTTP".to_string(),
+        port: 8080,
+        vpc_id: "vpc-test".to_string(),
+        health_check_path: None,
+        health_check_enabled: None,
+    };
+    cloud.create_target_group(&plan).await.expect("created");
+    let err = cloud.create_target_group(&plan).await.unwrap_err();
+    assert_eq!(err.kind, CloudErrorKind::AlreadyExists, "{}", err);
+}
+
+#[rocket::async_test]
+async fn throttled_step_is_recorded_with_its_code() {
+    let cloud = Arc::new(FakeCloud::new());
+    cloud.fail_with("create_load_balancer", CloudErrorKind::Throttled);
+    let client = client(cloud.clone()).await;
+
+    let (_, output) = post(&client, "/deploy/aws/create", &input("throttled")).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "RolledBack", "{}", record);
+    assert_eq!(record["error_code"], "CloudThrottled");
+    assert_eq!(record["retry_after"], 5);
+    let error = record["error"].as_str().unwrap();
+    assert!(error.contains("LoadBalancerCreationFailed"), "{}", error);
+    assert!(error.contains("request id"), "{}", error);
+    // every attempt but the first was a retry
+    assert_eq!(record["retries"]["create_load_balancer"], 4);
+}
+
+#[rocket::async_test]
+async fn failed_load_balancer_fails_the_deployment() {
+    let cloud = Arc::new(FakeCloud::new());
+    cloud.set_load_balancer_state("failed", Some("subnet has no free addresses"));
+    let client = client(cloud.clone()).await;
+
+    let (_, output) = post(&client, "/deploy/aws/create", &input("inactive")).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "RolledBack", "{}", record);
+    assert_eq!(record["error_code"], "LoadBalancerNotActive");
+    let error = record["error"].as_str().unwrap();
+    assert!(error.contains("subnet has no free addresses"), "{}", error);
+    assert!(cloud.resources().listeners.is_empty());
+}
+
+#[rocket::async_test]
+async fn load_balancer_wait_times_out_with_the_last_state() {
+    let cloud = Arc::new(FakeCloud::new());
+    cloud.set_load_balancer_state("provisioning", None);
+    let client = client(cloud.clone()).await;
+    let (_, output) = post(&client, "/deploy/aws/create", &input("slow")).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["error_code"], "LoadBalancerNotActive");
+    let error = record["error"].as_str().unwrap();
+    assert!(error.contains("last observed provisioning"), "{}", error);
+}
+
+#[rocket::async_test]
+async fn wait_for_healthy_waits_for_every_target_group() {
+    let cloud = Arc::new(FakeCloud::new());
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("healthy");
+    body["wait_for_healthy"] = json!(true);
+    body["targets"] = json!([{"port": 8080}, {"port": 9090}]);
+    let (_, output) = post(&client, "/deploy/aws/create", &body).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    let health = record["target_health"].as_object().unwrap();
+    assert_eq!(health.len(), 2);
+    assert!(health.values().all(|t| t[0]["state"] == "healthy"));
+
+    // targets without health checks have none to pass
+    let mut body = input("unchecked");
+    body["wait_for_healthy"] = json!(true);
+    body["targets"] = json!([{"port": 8080, "health_check_enabled": false}]);
+    let (_, output) = post(&client, "/deploy/aws/create", &body).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    let health = record["target_health"].as_object().unwrap();
+    let target = &health.values().next().unwrap()[0];
+    assert_eq!(target["state"], "unavailable");
+    assert_eq!(target["reason"], "Target.HealthCheckDisabled");
+}
+
+#[rocket::async_test]
+async fn a_queued_deployment_whose_environment_was_removed_fails() {
+    let cloud = Arc::new(FakeCloud::new());
+    let state = state(cloud.clone()).await;
+
+    let input: DeployAWSInput = serde_json::from_value(input("orphan")).unwrap();
+    let mut plan = state.plan(&input).expect("valid plan");
+    // as if the environment was removed before the job was requeued
+    plan.environment = "removed".to_string();
+    let id = "orphan-deployment".to_string();
+    state
+        .store
+        .insert(DeploymentRecord::new(
+            id.clone(),
+            input.stored(),
+            plan.clone(),
+        ))
+        .await
+        .unwrap();
+    let jobs = JobQueue::start(state.clone(), 1, 10);
+    jobs.enqueue(Job {
+        id: id.clone(),
+        work: Work::Provision {
+            plan: Box::new(plan),
+            files: vec![],
+        },
+        lock: state.slug_locks.acquire("orphan").unwrap(),
+    })
+    .unwrap();
+
+    for _ in 0..100 {
+        let record = state.store.get(&id).await.unwrap();
+        if record.phase != DeploymentPhase::Pending {
+            assert_eq!(record.phase, DeploymentPhase::Failed);
+            assert_eq!(record.error_code.as_deref(), Some("UnknownEnvironment"));
+            assert!(cloud.resources().is_empty(), "{:?}", cloud.resources());
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("deployment {} was left pending", id);
+}
+
+#[rocket::async_test]
+async fn a_deployment_interrupted_during_provisioning_fails_and_can_be_deleted() {
+    let cloud = Arc::new(FakeCloud::new());
+    let state = state(cloud.clone()).await;
+
+    // as if the service stopped right after creating the target groups
+    let input: DeployAWSInput = serde_json::from_value(input("interrupted")).unwrap();
+    let plan = state.plan(&input).expect("valid plan");
+    let mut record =
+        DeploymentRecord::new("interrupted-deployment".to_string(), input.stored(), plan);
+    for target_group in &record.plan.target_groups {
+        let arn = cloud.create_target_group(target_group).await.unwrap();
+        record.resources.target_group_arns.push(arn);
+    }
+    record.phase = DeploymentPhase::TargetGroupsCreated;
+    let id = record.id.clone();
+    state.store.insert(record).await.unwrap();
+
+    let jobs = JobQueue::start(state.clone(), 1, 10);
+    crate::jobs::recover(&state, &jobs).await;
+    let record = state.store.get(&id).await.unwrap();
+    assert_eq!(record.phase, DeploymentPhase::Failed);
+    assert_eq!(
+        record.error_code.as_deref(),
+        Some("ProvisioningInterrupted")
+    );
+    assert!(!cloud.resources().is_empty());
+
+    let client = Client::tracked(crate::rocket(state.clone(), jobs))
+        .await
+        .unwrap();
+    let record = delete(&client, &id).await;
+    assert_eq!(record["phase"], "Deleted", "{}", record);
+    assert!(cloud.resources().is_empty(), "{:?}", cloud.resources());
+}
+
+#[rocket::async_test]
+async fn a_restart_only_fails_the_deployments_it_interrupted() {
+    let cloud = Arc::new(FakeCloud::new());
+    let state = state(cloud.clone()).await;
+
+    // one deployment still queued and one a worker had started on
+    let mut ids = vec![];
+    for (slug, phase) in [
+        ("queued", DeploymentPhase::Pending),
+        ("started", DeploymentPhase::SecretsStored),
+    ] {
+        let input: DeployAWSInput = serde_json::from_value(input(slug)).unwrap();
+        let plan = state.plan(&input).expect("valid plan");
+        let mut record =
+            DeploymentRecord::new(format!("{}-deployment", slug), input.stored(), plan);
+        record.phase = phase;
+        ids.push(record.id.clone());
+        state.store.insert(record).await.unwrap();
+    }
+
+    // the workers are running before the deployments are recovered
+    let jobs = JobQueue::start(state.clone(), 1, 10);
+    crate::jobs::recover(&state, &jobs).await;
+    let client = Client::tracked(crate::rocket(state.clone(), jobs))
+        .await
+        .unwrap();
+
+    let queued = wait_for(&client, &ids[0]).await;
+    assert_eq!(queued["phase"], "Ready", "{}", queued);
+    assert!(queued["error_code"].is_null(), "{}", queued);
+    let started = wait_for(&client, &ids[1]).await;
+    assert_eq!(started["phase"], "Failed", "{}", started);
+    assert_eq!(started["error_code"], "ProvisioningInterrupted");
+}
+
+#[rocket::async_test]
+async fn unhealthy_targets_fail_the_deployment_with_their_reasons() {
+    let cloud = Arc::new(FakeCloud::new());
+    cloud.set_target_health("unhealthy", Some("Target.FailedHealthChecks"));
+    let client = client(cloud.clone()).await;
+
+    let mut body = input("unhealthy");
+    body["wait_for_healthy"] = json!(true);
+    let (_, output) = post(&client, "/deploy/aws/create", &body).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "RolledBack", "{}", record);
+    assert_eq!(record["error_code"], "TargetsNotHealthy");
+    let error = record["error"].as_str().unwrap();
+    assert!(error.contains("Target.FailedHealthChecks"), "{}", error);
+    let targets = record["target_health"].as_object().unwrap();
+    let target = &targets.values().next().unwrap()[0];
+    assert_eq!(target["reason"], "Target.FailedHealthChecks");
+    assert!(cloud.resources().is_empty(), "{:?}", cloud.resources());
+
+    // without waiting the same deployment is ready straight away
+    let (_, output) = post(&client, "/deploy/aws/create", &input("unchecked")).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+}
+
+#[rocket::async_test]
+async fn transient_failures_are_retried() {
+    let cloud = Arc::new(FakeCloud::new());
+    cloud.fail_times("create_target_group", CloudErrorKind::Throttled, 2);
+    cloud.fail_times("authorize_ingress", CloudErrorKind::NotFound, 1);
+    let client = client(cloud.clone()).await;
+
+    let (_, output) = post(&client, "/deploy/aws/create", &input("flaky")).await;
+    let record = wait_for(&client, output["id"].as_str().unwrap()).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    assert_eq!(
+        record["retries"],
+        json!({"create_target_group": 2, "authorize_ingress": 1})
+    );
+}
+
+#[test]
+fn aws_errors_map_to_statuses() {
+    let cases = [
+        ("Throttling", CloudErrorKind::Throttled, 503),
+        ("RequestLimitExceeded", CloudErrorKind::Throttled, 503),
+        ("InvalidGroup.Duplicate", CloudErrorKind::AlreadyExists, 409),
+        (
+            "DuplicateTargetGroupName",
+            CloudErrorKind::AlreadyExists,
+            409,
+        ),
+        ("InvalidGroup.NotFound", CloudErrorKind::NotFound, 404),
+        ("ValidationError", CloudErrorKind::InvalidRequest, 400),
+        ("InvalidParameterValue", CloudErrorKind::InvalidRequest, 400),
+        ("TooManyLoadBalancers", CloudErrorKind::LimitExceeded, 429),
+        ("VcpuLimitExceeded", CloudErrorKind::LimitExceeded, 429),
+        ("UnauthorizedOperation", CloudErrorKind::AccessDenied, 502),
+        ("InvalidClientTokenId", CloudErrorKind::AccessDenied, 502),
+        ("DependencyViolation", CloudErrorKind::Other, 500),
+    ];
+    for (code, kind, status) in cases {
+        let error = Error::from(CloudError::from_code(code, "message", Some("req-1")));
+        assert_eq!(CloudErrorKind::from_code(code), kind, "{}", code);
+        assert_eq!(error.http_status_code, status, "{}", code);
+        assert_eq!(error.err, kind.code());
+        assert_eq!(error.request_id.as_deref(), Some("req-1"));
+    }
+    let throttled = Error::from(CloudError::from_code("Throttling", "slow down", None));
+    assert_eq!(throttled.retry_after, Some(5));
+}
@@ -0,0 +1,344 @@
+//! End-to-end tests against a local AWS emulator
+//!
+//! These are ignored by default. Start an emulator and point the tests at it:
+//!
+//! ```text
+//! moto_server -p 5000 &
+//! AWS_EMULATOR_ENDPOINT=http://localhost:5000 cargo test -- --ignored
+//! ```
+//!
+//! LocalStack works as well, with an edition that emulates Auto Scaling and
+//! ELBv2.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rocket::figment::providers::{Format, Toml};
+use rocket::figment::Figment;
+use rocket::http::Status;
+use serde_json::{json, Value};
+
+use super::{client_with, delete, input, post, wait_for};
+use crate::aws::{self, AwsProvider};
+use crate::cloud::CloudProvider;
+use crate::config::Config;
+use crate::plan::{
+    ListenerAction, ListenerPlan, LoadBalancerPlan, SecurityGroupPlan, HTTPS_PORT, HTTP_PORT,
+};
+
+const DEFAULT_ENDPOINT: &str = "http://localhost:5000";
+
+struct Network {
+    vpc_id: String,
+    subnets: Vec<String>,
+    hosted_zone_id: String,
+}
+
+fn config(endpoint: &str, network: &Network) -> Config {
+    Config::load(&Figment::from(Toml::string(&config_toml(
+        endpoint, network,
+    ))))
+    .expect("valid config")
+}
+
+fn config_toml(endpoint: &str, network: &Network) -> String {
+    let subnets = network
+        .subnets
+        .iter()
+        .map(|s| format!("{:?}", s))
+        .collect::<Vec<String>>()
+        .join(", ");
+    format!(
+        r#"
+default_environment = "emulator"
+
+[environments.emulator]
+region = "us-east-1"
+vpc_id = "{vpc_id}"
+instance_subnets = [{subnets}]
+public_subnets = [{subnets}]
+hosted_zone_id = "{hosted_zone_id}"
+image_id = "ami-12c6146b"
+instance_profile = "flakery-deployment"
+
+[environments.emulator.aws]
+endpoint_url = "{endpoint}"
+access_key_id = "test"
+secret_access_key = "test"
+
+[environments.emulator.secrets]
+backend = "ssm"
+"#,
+        vpc_id = network.vpc_id,
+        hosted_zone_id = network.hosted_zone_id,
+    )
+}
+
+/// Create the VPC, subnets and hosted zone an environment expects to exist
+async fn network(sdk_config: &aws_config::SdkConfig) -> Network {
+    let ec2 = aws_sdk_ec2::Client::new(sdk_config);
+    let vpc = ec2
+        .create_vpc()
+        .cidr_block("10.0.0.0/16")
+        .send()
+        .await
+        .expect("vpc created");
+    let vpc_id = vpc.vpc().and_then(|v| v.vpc_id()).unwrap().to_string();
+
+    let mut subnets = vec![];
+    for (cidr, zone) in [("10.0.1.0/24", "us-east-1a"), ("10.0.2.0/24", "us-east-1b")] {
+        let subnet = ec2
+            .create_subnet()
+            .vpc_id(&vpc_id)
+            .cidr_block(cidr)
+            .availability_zone(zone)
+            .send()
+            .await
+            .expect("subnet created");
+        subnets.push(
+            subnet
+                .subnet()
+                .and_then(|s| s.subnet_id())
+                .unwrap()
+                .to_string(),
+        );
+    }
+
+    // load balancers are internet-facing, so their subnets need a route to
+    // an internet gateway
+    let gateway = ec2
+        .create_internet_gateway()
+        .send()
+        .await
+        .expect("internet gateway created");
+    let gateway_id = gateway
+        .internet_gateway()
+        .and_then(|g| g.internet_gateway_id())
+        .unwrap()
+        .to_string();
+    ec2.attach_internet_gateway()
+        .internet_gateway_id(&gateway_id)
+        .vpc_id(&vpc_id)
+        .send()
+        .await
+        .expect("internet gateway attached");
+    let route_table = ec2
+        .create_route_table()
+        .vpc_id(&vpc_id)
+        .send()
+        .await
+        .expect("route table created");
+    let route_table_id = route_table
+        .route_table()
+        .and_then(|t| t.route_table_id())
+        .unwrap()
+        .to_string();
+    ec2.create_route()
+        .route_table_id(&route_table_id)
+        .destination_cidr_block("0.0.0.0/0")
+        .gateway_id(&gateway_id)
+        .send()
+        .await
+        .expect("route created");
+    for subnet in &subnets {
+        ec2.associate_route_table()
+            .route_table_id(&route_table_id)
+            .subnet_id(subnet)
+            .send()
+            .await
+            .expect("route table associated");
+    }
+
+    let zone = aws_sdk_route53::Client::new(sdk_config)
+        .create_hosted_zone()
+        .name("example.test")
+        .caller_reference(uuid::Uuid::new_v4().to_string())
+        .send()
+        .await
+        .expect("hosted zone created");
+    let hosted_zone_id = zone
+        .hosted_zone()
+        .map(|z| z.id().trim_start_matches("/hostedzone/").to_string())
+        .unwrap();
+
+    Network {
+        vpc_id,
+        subnets,
+        hosted_zone_id,
+    }
+}
+
+/// The emulator's endpoint, the SDK configuration to reach it with, and a
+/// fresh network in it
+async fn emulator() -> (String, aws_config::SdkConfig, Network) {
+    let endpoint =
+        std::env::var("AWS_EMULATOR_ENDPOINT").unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string());
+
+    // the placeholder network is only used to reach the emulator
+    let placeholder = config(
+        &endpoint,
+        &Network {
+            vpc_id: "vpc-placeholder".to_string(),
+            subnets: vec!["subnet-placeholder".to_string()],
+            hosted_zone_id: "ZPLACEHOLDER".to_string(),
+        },
+    );
+    let sdk_config = aws::sdk_config(&placeholder.environments["emulator"]).await;
+    let network = network(&sdk_config).await;
+    (endpoint, sdk_config, network)
+}
+
+#[rocket::async_test]
+#[ignore = "needs a local AWS emulator, see the module docs"]
+async fn create_and_delete_against_emulator() {
+    let (endpoint, sdk_config, network) = emulator().await;
+
+    let config = config(&endpoint, &network);
+    let provider = AwsProvider::new(&config.environments["emulator"]).await;
+    let mut providers: HashMap<String, Arc<dyn CloudProvider>> = HashMap::new();
+    providers.insert("emulator".to_string(), Arc::new(provider));
+    let client = client_with(config, providers).await;
+
+    let slug = format!("e2e-{}", &uuid::Uuid::new_v4().to_string()[..8]);
+    let mut body = input(&slug);
+    body["wait_for_healthy"] = Value::Bool(true);
+    body["targets"] = json!([{"port": 8080}, {"port": 9000, "path_pattern": "/ws/*"}]);
+    let (status, output) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Accepted, "{}", output);
+    assert_eq!(output["fqdn"], format!("{}.example.test", slug));
+    let id = output["id"].as_str().expect("deployment id");
+
+    let record = wait_for(&client, id).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    assert!(!record["target_health"].as_object().unwrap().is_empty());
+    let resources = &record["resources"];
+    for key in [
+        "launch_template_id",
+        "auto_scaling_group_name",
+        "load_balancer_security_group_id",
+        "instance_security_group_id",
+        "load_balancer_arn",
+    ] {
+        assert!(!resources[key].is_null(), "{} missing: {}", key, record);
+    }
+    assert_eq!(resources["record_sets"].as_array().unwrap().len(), 2);
+    assert_eq!(resources["listener_arns"].as_array().unwrap().len(), 2);
+    assert_eq!(resources["listener_rule_arns"].as_array().unwrap().len(), 1);
+
+    let ssm = aws_sdk_ssm::Client::new(&sdk_config);
+    let parameters = ssm
+        .get_parameters_by_path()
+        .path(format!("/flakery/{}", slug))
+        .with_decryption(true)
+        .send()
+        .await
+        .expect("parameters listed");
+    assert_eq!(parameters.parameters().len(), 2);
+
+    let groups = aws_sdk_autoscaling::Client::new(&sdk_config)
+        .describe_auto_scaling_groups()
+        .auto_scaling_group_names(format!("{}-asg", slug))
+        .send()
+        .await
+        .expect("groups described");
+    assert_eq!(groups.auto_scaling_groups().len(), 1);
+
+    let record = delete(&client, id).await;
+    assert_eq!(record["phase"], "Deleted", "{}", record);
+
+    let groups = aws_sdk_autoscaling::Client::new(&sdk_config)
+        .describe_auto_scaling_groups()
+        .auto_scaling_group_names(format!("{}-asg", slug))
+        .send()
+        .await
+        .expect("groups described");
+    assert!(groups.auto_scaling_groups().is_empty());
+    let parameters = ssm
+        .get_parameters_by_path()
+        .path(format!("/flakery/{}", slug))
+        .send()
+        .await
+        .expect("parameters listed");
+    assert!(parameters.parameters().is_empty());
+}
+
+#[rocket::async_test]
+#[ignore = "needs a local AWS emulator, see the module docs"]
+async fn shared_load_balancer_against_emulator() {
+    let (endpoint, _, network) = emulator().await;
+
+    // provision the shared load balancer the way operators would
+    let config = config(&endpoint, &network);
+    let provider = Arc::new(AwsProvider::new(&config.environments["emulator"]).await);
+    let security_group_id = provider
+        .create_security_group(&SecurityGroupPlan {
+            name: format!("shared-{}", uuid::Uuid::new_v4()),
+            description: "Shared load balancer".to_string(),
+            vpc_id: network.vpc_id.clone(),
+            ingress: vec![],
+        })
+        .await
+        .expect("security group created");
+    let load_balancer = provider
+        .create_load_balancer(
+            &LoadBalancerPlan {
+                name: format!("shared-{}", &uuid::Uuid::new_v4().to_string()[..8]),
+                scheme: Default::default(),
+                subnets: network.subnets.clone(),
+            },
+            &security_group_id,
+        )
+        .await
+        .expect("load balancer created");
+    let listener_arn = provider
+        .create_listener(
+            &load_balancer.arn,
+            &ListenerPlan {
+                protocol: "HTTP".to_string(),
+                port: HTTP_PORT,
+                action: ListenerAction::RedirectToHttps(HTTPS_PORT),
+                rules: vec![],
+            },
+            None,
+            None,
+        )
+        .await
+        .expect("listener created");
+
+    let toml = format!(
+        "{}\n[environments.emulator.shared_load_balancer]\narn = {:?}\nlistener_arn = {:?}\nsecurity_group_id = {:?}\n",
+        config_toml(&endpoint, &network),
+        load_balancer.arn,
+        listener_arn,
+        security_group_id
+    );
+    let config = Config::load(&Figment::from(Toml::string(&toml))).expect("valid config");
+    let mut providers: HashMap<String, Arc<dyn CloudProvider>> = HashMap::new();
+    providers.insert("emulator".to_string(), provider.clone());
+    let client = client_with(config, providers).await;
+
+    let slug = format!("e2e-{}", &uuid::Uuid::new_v4().to_string()[..8]);
+    let mut body = input(&slug);
+    body["shared_load_balancer"] = Value::Bool(true);
+    let (status, output) = post(&client, "/deploy/aws/create", &body).await;
+    assert_eq!(status, Status::Accepted, "{}", output);
+    let id = output["id"].as_str().expect("deployment id");
+    let record = wait_for(&client, id).await;
+    assert_eq!(record["phase"], "Ready", "{}", record);
+    assert!(record["resources"]["load_balancer_arn"].is_null());
+    assert_eq!(
+        provider
+            .listener_rule_priorities(&listener_arn)
+            .await
+            .unwrap(),
+        [1]
+    );
+
+    let record = delete(&client, id).await;
+    assert_eq!(record["phase"], "Deleted", "{}", record);
+    assert!(provider
+        .listener_rule_priorities(&listener_arn)
+        .await
+        .unwrap()
+        .is_empty());
+}
@@ -0,0 +1,453 @@
+use std::net::IpAddr;
+
+use crate::cloud::CloudProvider;
+use crate::config::{Config, Environment};
+use crate::error::{self, FieldError};
+use crate::files;
+use crate::naming;
+use crate::plan::{Scheme, HTTPS_PORT, HTTP_PORT};
+use crate::secrets::SecretsPlan;
+use crate::{DeployAWSInput, Target};
+
+/// DNS names are limited to 253 characters, and each label to 63
+const MAX_DOMAIN_NAME_LENGTH: usize = 253;
+const MAX_DOMAIN_LABEL_LENGTH: usize = 63;
+
+const MAX_RULE_CONDITION_LENGTH: usize = 128;
+
+/// EC2 tag values are limited to 256 characters
+const MAX_TAG_VALUE_LENGTH: usize = 256;
+
+/// Collects the problems found in a request
+#[derive(Default)]
+struct Problems(Vec<FieldError>);
+
+impl Problems {
+    fn add(&mut self, field: &str, code: &str, message: String) {
+        self.0.push(FieldError {
+            field: field.to_string(),
+            code: code.to_string(),
+            message,
+        });
+    }
+
+    /// Record `result`'s error, if any, against `field`
+    fn check(&mut self, field: &str, result: Result<(), error::Error>) {
+        if let Err(e) = result {
+            self.add(field, &e.err, e.msg.unwrap_or_default());
+        }
+    }
+}
+
+/// Check everything about `input` that can be checked without calling AWS,
+/// including what the environment it is deployed to allows, rejecting it with
+/// a 422 listing every invalid field
+pub fn validate(input: &DeployAWSInput, config: &Config) -> Result<(), error::Error> {
+    let mut problems = Problems::default();
+
+    problems.check(
+        "deployment_slug",
+        naming::validate_slug(&input.deployment_slug),
+    );
+    check_flake_url(&mut problems, &input.flake_url);
+    check_subdomain_prefix(&mut problems, &input.subdomain_prefix);
+    match config.environment(input.environment.as_deref()) {
+        Ok((_, environment)) => check_environment(&mut problems, input, environment),
+        Err(e) => problems.check("environment", Err(e)),
+    }
+    if input.template_id.trim().is_empty() {
+        problems.add(
+            "template_id",
+            "Required",
+            "template_id must not be empty".to_string(),
+        );
+    }
+
+    let min_size = input.min_size.unwrap_or(1);
+    let max_size = input.max_size.unwrap_or(1);
+    if min_size < 0 {
+        problems.add(
+            "min_size",
+            "OutOfRange",
+            format!("min_size is {}, it may not be negative", min_size),
+        );
+    }
+    if max_size < 1 {
+        problems.add(
+            "max_size",
+            "OutOfRange",
+            format!("max_size is {}, it must be at least 1", max_size),
+        );
+    }
+    if min_size > max_size {
+        problems.add(
+            "min_size",
+            "MinSizeAboveMaxSize",
+            format!(
+                "min_size {} is greater than max_size {}",
+                min_size, max_size
+            ),
+        );
+    }
+
+    let https = input.https == Some(true);
+    let any_routed = input.targets.iter().flatten().any(|t| t.routed());
+    for (i, target) in input.targets.iter().flatten().enumerate() {
+        if !(1..=65535).contains(&target.port) {
+            problems.add(
+                &format!("targets[{}].port", i),
+                "PortOutOfRange",
+                format!("port {} is not between 1 and 65535", target.port),
+            );
+        } else if input
+            .targets
+            .iter()
+            .flatten()
+            .take(i)
+            .any(|t| t.port == target.port)
+        {
+            problems.add(
+                &format!("targets[{}].port", i),
+                "DuplicateTargetPort",
+                format!("port {} is used by more than one target", target.port),
+            );
+        }
+        // routed targets don't get a listener on their own port
+        let reserved: &[i64] = if target.routed() {
+            &[]
+        } else if https && i > 0 {
+            &[HTTP_PORT, HTTPS_PORT]
+        } else if !https && any_routed {
+            &[HTTP_PORT]
+        } else {
+            &[]
+        };
+        if reserved.contains(&target.port) {
+            problems.add(
+                &format!("targets[{}].port", i),
+                "ReservedListenerPort",
+                format!(
+                    "port {} is taken by the listener routed targets share, or the redirect listener",
+                    target.port
+                ),
+            );
+        }
+        check_route(&mut problems, i, target);
+        if target.routed()
+            && input.targets.iter().flatten().take(i).any(|t| {
+                t.path_pattern == target.path_pattern && t.host_header == target.host_header
+            })
+        {
+            problems.add(
+                &format!("targets[{}]", i),
+                "DuplicateRoute",
+                "another target has the same path_pattern and host_header".to_string(),
+            );
+        }
+        if let Some(path) = &target.health_check_path {
+            if !path.starts_with('/') {
+                problems.add(
+                    &format!("targets[{}].health_check_path", i),
+                    "InvalidHealthCheckPath",
+                    format!("health check path {} must start with /", path),
+                );
+            }
+        }
+    }
+
+    if let Some(cidrs) = &input.ingress_cidrs {
+        if cidrs.is_empty() {
+            problems.add(
+                "ingress_cidrs",
+                "Required",
+                "ingress_cidrs must list at least one CIDR block".to_string(),
+            );
+        }
+        for (i, cidr) in cidrs.iter().enumerate() {
+            if !valid_cidr(cidr) {
+                problems.add(
+                    &format!("ingress_cidrs[{}]", i),
+                    "InvalidCidr",
+                    format!("{} is not a CIDR block such as 10.0.0.0/8", cidr),
+                );
+            }
+        }
+    }
+
+    if input.shared_load_balancer == Some(true) {
+        check_shared_load_balancer(&mut problems, input);
+    }
+
+    for (i, file) in input.files.iter().flatten().enumerate() {
+        problems.check(
+            &format!("files[{}]", i),
+            files::plan(std::slice::from_ref(file)).map(|_| ()),
+        );
+    }
+
+    if problems.0.is_empty() {
+        Ok(())
+    } else {
+        Err(error::Error::invalid_fields(problems.0))
+    }
+}
+
+/// Check the instance type is one the environment allows, and that the values
+/// instances are tagged with fit in a tag
+fn check_environment(problems: &mut Problems, input: &DeployAWSInput, environment: &Environment) {
+    let allowed = &environment.allowed_instance_types;
+    if !allowed.contains(&input.instance_type) {
+        problems.add(
+            "instance_type",
+            "InvalidInstanceType",
+            format!(
+                "instance type {} is not allowed, expected one of: {}",
+                input.instance_type,
+                allowed.join(", ")
+            ),
+        );
+    }
+
+    // the secrets are looked up under a reference derived from the slug
+    let secrets = SecretsPlan::new(&environment.secrets, &input.deployment_slug);
+    let tags = [
+        ("template_id", "template_id", &input.template_id),
+        ("flake_url", "flake_url", &input.flake_url),
+        ("deployment_slug", "secrets_reference", &secrets.reference),
+    ];
+    for (field, tag, value) in tags {
+        if value.chars().count() > MAX_TAG_VALUE_LENGTH {
+            problems.add(
+                field,
+                "TagValueTooLong",
+                format!(
+                    "the {} instance tag may be at most {} characters",
+                    tag, MAX_TAG_VALUE_LENGTH
+                ),
+            );
+        }
+    }
+}
+
+/// Check that the subnets load balancers of `scheme` are placed in route to
+/// an internet gateway if they are internet-facing, and don't if internal
+pub async fn check_subnets(
+    cloud: &dyn CloudProvider,
+    scheme: Scheme,
+    subnets: &[String],
+) -> Result<(), error::Error> {
+    let internet_facing = scheme == Scheme::InternetFacing;
+    let mut mismatched = vec![];
+    for subnet in subnets {
+        let public = cloud
+            .subnet_is_public(subnet)
+            .await
+            .map_err(|e| e.during("SubnetLookupFailed"))?;
+        if public != internet_facing {
+            mismatched.push(subnet.as_str());
+        }
+    }
+    if mismatched.is_empty() {
+        return Ok(());
+    }
+    Err(error::Error::new(
+        "SubnetSchemeMismatch",
+        Some(&format!(
+            "{} load balancers can't be placed in {} subnets {}",
+            scheme.as_str(),
+            if internet_facing { "private" } else { "public" },
+            mismatched.join(", ")
+        )),
+        500,
+    ))
+}
+
+/// ACM validates a certificate through a record in the public hosted zone,
+/// so a name from a private zone can only get one if both zones have the same
+/// domain
+pub fn check_certificate_domain(
+    zone_name: &str,
+    public_zone_name: &str,
+) -> Result<(), error::Error> {
+    if zone_name == public_zone_name {
+        return Ok(());
+    }
+    Err(error::Error::invalid_fields(vec![FieldError {
+        field: "https".to_string(),
+        code: "CertificateNotValidatable".to_string(),
+        message: format!(
+            "the private hosted zone's domain {} is not the public zone's {}, where the certificate would be validated",
+            zone_name, public_zone_name
+        ),
+    }]))
+}
+
+/// A shared load balancer routes one target per deployment by host name,
+/// which its wildcard certificate only covers one label deep, and only admits
+/// who its own security group does
+fn check_shared_load_balancer(problems: &mut Problems, input: &DeployAWSInput) {
+    if input.targets.as_ref().is_some_and(|t| t.len() > 1) {
+        problems.add(
+            "targets",
+            "SharedLoadBalancerSingleTarget",
+            "deployments on the shared load balancer have a single target".to_string(),
+        );
+    }
+    if let Some(i) = input
+        .targets
+        .iter()
+        .flatten()
+        .position(|t| t.host_header.is_some())
+    {
+        problems.add(
+            &format!("targets[{}].host_header", i),
+            "NotSupportedWithSharedLoadBalancer",
+            "the shared load balancer routes the deployment's own name to it".to_string(),
+        );
+    }
+    if input.subdomain_prefix.contains('.') {
+        problems.add(
+            "subdomain_prefix",
+            "NotCoveredByWildcardCertificate",
+            format!(
+                "subdomain_prefix {:?} must be a single label to be covered by the shared load balancer's wildcard certificate",
+                input.subdomain_prefix
+            ),
+        );
+    }
+    if input.ingress_cidrs.is_some() {
+        problems.add(
+            "ingress_cidrs",
+            "NotSupportedWithSharedLoadBalancer",
+            "the shared load balancer's security group decides who may reach it".to_string(),
+        );
+    }
+    if input.https == Some(true) {
+        problems.add(
+            "https",
+            "NotSupportedWithSharedLoadBalancer",
+            "the shared load balancer's listener serves its wildcard certificate".to_string(),
+        );
+    }
+}
+
+/// Listener rule conditions are at most 128 characters, and may use `*` and
+/// `?` wildcards
+fn check_route(problems: &mut Problems, i: usize, target: &Target) {
+    if let Some(pattern) = &target.path_pattern {
+        if !pattern.starts_with('/')
+            || pattern.len() > MAX_RULE_CONDITION_LENGTH
+            || pattern.chars().any(|c| c.is_whitespace() || c.is_control())
+        {
+            problems.add(
+                &format!("targets[{}].path_pattern", i),
+                "InvalidPathPattern",
+                format!(
+                    "path_pattern {:?} must start with / and be at most {} characters without whitespace",
+                    pattern, MAX_RULE_CONDITION_LENGTH
+                ),
+            );
+        }
+    }
+    if let Some(host) = &target.host_header {
+        if host.is_empty()
+            || host.len() > MAX_RULE_CONDITION_LENGTH
+            || !host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-.*?".contains(c))
+        {
+            problems.add(
+                &format!("targets[{}].host_header", i),
+                "InvalidHostHeader",
+                format!(
+                    "host_header {:?} must be a host name of at most {} characters, e.g. api.example.com",
+                    host, MAX_RULE_CONDITION_LENGTH
+                ),
+            );
+        }
+    }
+}
+
+/// Whether `cidr` is an IPv4 or IPv6 CIDR block, e.g. `10.0.0.0/16`
+pub fn valid_cidr(cidr: &str) -> bool {
+    let Some((address, prefix)) = cidr.split_once('/') else {
+        return false;
+    };
+    let max_prefix = match address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => 32,
+        Ok(IpAddr::V6(_)) => 128,
+        Err(_) => return false,
+    };
+    matches!(prefix.parse::<u8>(), Ok(p) if p <= max_prefix)
+}
+
+/// Flake references look like `<type>:<location>`, e.g. `github:owner/repo`
+/// or `git+https://example.com/repo`, and end up in a shell command on the
+/// instance, so whitespace and quotes are rejected
+fn check_flake_url(problems: &mut Problems, flake_url: &str) {
+    let valid_type = |t: &str| {
+        t.starts_with(|c: char| c.is_ascii_lowercase())
+            && t.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c))
+    };
+    let valid = match flake_url.split_once(':') {
+        Some((flake_type, location)) => {
+            valid_type(flake_type)
+                && !location.is_empty()
+                && !location
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control() || "'\"`\\".contains(c))
+        }
+        None => false,
+    };
+    if !valid {
+        problems.add(
+            "flake_url",
+            "InvalidFlakeUrl",
+            format!(
+                "flake_url {:?} is not a flake reference such as github:owner/repo#output",
+                flake_url
+            ),
+        );
+    }
+}
+
+/// The prefix is the start of a DNS name, one or more dot separated labels of
+/// letters, digits and hyphens
+fn check_subdomain_prefix(problems: &mut Problems, prefix: &str) {
+    if prefix.is_empty() {
+        problems.add(
+            "subdomain_prefix",
+            "Required",
+            "subdomain_prefix must not be empty".to_string(),
+        );
+        return;
+    }
+    if prefix.len() > MAX_DOMAIN_NAME_LENGTH {
+        problems.add(
+            "subdomain_prefix",
+            "TooLong",
+            format!(
+                "subdomain_prefix may be at most {} characters",
+                MAX_DOMAIN_NAME_LENGTH
+            ),
+        );
+    }
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= MAX_DOMAIN_LABEL_LENGTH
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if !prefix.split('.').all(valid_label) {
+        problems.add(
+            "subdomain_prefix",
+            "InvalidDomainName",
+            format!(
+                "subdomain_prefix {:?} must be dot separated labels of at most {} letters, digits and hyphens",
+                prefix, MAX_DOMAIN_LABEL_LENGTH
+            ),
+        );
+    }
+}
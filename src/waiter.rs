@@ -0,0 +1,85 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::WaitConfig;
+use crate::error::{CloudError, CloudErrorKind};
+
+/// What a single check of the awaited resource found
+pub enum Check<T> {
+    Done(T),
+    /// Not there yet, with a description of what was observed
+    Waiting(String),
+    /// Will never get there, e.g. the resource is in a failed state
+    Failed(String),
+}
+
+/// Why waiting stopped without the resource getting there
+#[derive(Debug)]
+pub enum WaitError {
+    Cloud(CloudError),
+    /// The resource reached a state it won't leave; the observed state
+    Failed(String),
+    /// The state last observed before the timeout expired
+    TimedOut(String),
+}
+
+impl WaitError {
+    /// The observed state, or the error, as shown to users
+    pub fn describe(&self) -> String {
+        match self {
+            WaitError::Cloud(e) => e.to_string(),
+            WaitError::Failed(state) => state.clone(),
+            WaitError::TimedOut(state) => format!("timed out, last observed {}", state),
+        }
+    }
+}
+
+/// Polls a resource until it reaches a state or a timeout expires
+pub struct Waiter {
+    timeout: Duration,
+    poll_interval: Duration,
+}
+
+impl Waiter {
+    pub fn new(config: &WaitConfig) -> Self {
+        Self {
+            timeout: Duration::from_millis(config.timeout_ms),
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+        }
+    }
+
+    /// Run `check` every poll interval until it is done, fails or the
+    /// timeout expires
+    ///
+    /// Throttling and unavailability only count as another observation, as
+    /// the next poll is as good as a retry.
+    pub async fn until<T, F, Fut>(&self, mut check: F) -> Result<T, WaitError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<Check<T>, CloudError>>,
+    {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let last = match check().await {
+                Ok(Check::Done(value)) => return Ok(value),
+                Ok(Check::Failed(state)) => return Err(WaitError::Failed(state)),
+                Ok(Check::Waiting(state)) => state,
+                Err(e)
+                    if matches!(
+                        e.kind,
+                        CloudErrorKind::Throttled | CloudErrorKind::Unavailable
+                    ) =>
+                {
+                    e.to_string()
+                }
+                Err(e) => return Err(WaitError::Cloud(e)),
+            };
+            if Instant::now() + self.poll_interval > deadline {
+                return Err(WaitError::TimedOut(last));
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+}